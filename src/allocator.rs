@@ -1,23 +1,29 @@
 #[cfg(feature = "metered-allocator")]
 use crate::MeteredAllocator;
 
-#[cfg(feature = "mimalloc")]
-pub use ::mimalloc::MiMalloc;
+// Jemalloc takes precedence if both are enabled, e.g. with `--all-features`.
+#[cfg(feature = "jemalloc")]
+use tikv_jemallocator::Jemalloc as Inner;
 
-#[cfg(all(not(feature = "mimalloc"), feature = "metered-allocator"))]
-use std::alloc::System;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+use ::mimalloc::MiMalloc as Inner;
 
-#[cfg(all(not(feature = "mimalloc"), feature = "metered-allocator"))]
-#[global_allocator]
-pub static ALLOCATOR: MeteredAllocator<System> = MeteredAllocator::new(System);
+#[cfg(all(
+    not(any(feature = "mimalloc", feature = "jemalloc")),
+    feature = "metered-allocator"
+))]
+use std::alloc::System as Inner;
 
-#[cfg(all(feature = "mimalloc", not(feature = "metered-allocator")))]
+#[cfg(feature = "metered-allocator")]
 #[global_allocator]
-pub static ALLOCATOR: MiMalloc = MiMalloc;
+pub static ALLOCATOR: MeteredAllocator<Inner> = MeteredAllocator::new(Inner);
 
-#[cfg(all(feature = "mimalloc", feature = "metered-allocator"))]
+#[cfg(all(
+    any(feature = "mimalloc", feature = "jemalloc"),
+    not(feature = "metered-allocator")
+))]
 #[global_allocator]
-pub static ALLOCATOR: MeteredAllocator<MiMalloc> = MeteredAllocator::new(MiMalloc);
+pub static ALLOCATOR: Inner = Inner;
 
 pub fn start_metering() {
     #[cfg(feature = "metered-allocator")]
@@ -25,3 +31,202 @@ pub fn start_metering() {
         ALLOCATOR.start_metering();
     }
 }
+
+#[cfg(any(
+    feature = "mimalloc",
+    feature = "jemalloc",
+    feature = "metered-allocator"
+))]
+pub use self::options::*;
+
+#[cfg(any(
+    feature = "mimalloc",
+    feature = "jemalloc",
+    feature = "metered-allocator"
+))]
+mod options {
+    use crate::default_from_clap;
+    use clap::Parser;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static PRINT_STATS: AtomicBool = AtomicBool::new(false);
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+    #[group(skip)]
+    pub struct Options {
+        /// Print allocator statistics to stderr on exit.
+        #[clap(long, env)]
+        allocator_stats: bool,
+    }
+
+    default_from_clap!(Options);
+
+    impl Options {
+        pub fn init(self) {
+            PRINT_STATS.store(self.allocator_stats, Ordering::Relaxed);
+        }
+    }
+
+    /// Print allocator statistics if `--allocator-stats` was given.
+    pub fn print_stats_on_exit() {
+        if !PRINT_STATS.load(Ordering::Relaxed) {
+            return;
+        }
+
+        #[cfg(feature = "metered-allocator")]
+        {
+            use crate::metered_allocator::{allocator_stats, kind_totals, Bytes, ThreadKind};
+
+            eprintln!("Metered allocator: {}", allocator_stats());
+            for kind in ThreadKind::ALL {
+                let totals = kind_totals(kind);
+                eprintln!(
+                    "  {kind:<8} allocated {} in {} allocations and {} reallocations, freed {}",
+                    Bytes(totals.allocated),
+                    totals.allocs,
+                    totals.reallocs,
+                    Bytes(totals.freed)
+                );
+            }
+        }
+
+        super::native::print_stats();
+    }
+}
+
+#[cfg(any(feature = "mimalloc", feature = "jemalloc"))]
+pub use self::native::{native_stats, NativeStats};
+
+/// Statistics reported by jemalloc.
+#[cfg(feature = "jemalloc")]
+mod native {
+    use tikv_jemalloc_ctl::{epoch, stats, stats_print};
+    use tracing::error;
+
+    /// Statistics reported by the allocator, in bytes.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct NativeStats {
+        /// Bytes allocated by the application.
+        pub allocated: usize,
+        /// Bytes in pages allocated by the application.
+        pub active:    usize,
+        /// Bytes in physically resident data pages mapped by the allocator.
+        pub resident:  usize,
+        /// Bytes in virtual memory mappings retained rather than unmapped.
+        pub retained:  usize,
+    }
+
+    impl NativeStats {
+        pub const ALLOCATOR: &'static str = "jemalloc";
+
+        /// Statistics by name.
+        #[must_use]
+        pub const fn fields(&self) -> [(&'static str, usize); 4] {
+            [
+                ("allocated", self.allocated),
+                ("active", self.active),
+                ("resident", self.resident),
+                ("retained", self.retained),
+            ]
+        }
+    }
+
+    /// Read the current allocator statistics.
+    #[must_use]
+    pub fn native_stats() -> NativeStats {
+        // Statistics are cached until the epoch is advanced.
+        if let Err(err) = epoch::advance() {
+            error!(?err, "Error reading jemalloc statistics: {}", err);
+            return NativeStats::default();
+        }
+        NativeStats {
+            allocated: stats::allocated::read().unwrap_or_default(),
+            active:    stats::active::read().unwrap_or_default(),
+            resident:  stats::resident::read().unwrap_or_default(),
+            retained:  stats::retained::read().unwrap_or_default(),
+        }
+    }
+
+    pub fn print_stats() {
+        let options = stats_print::Options::default();
+        if let Err(err) = stats_print::stats_print(std::io::stderr(), options) {
+            error!(?err, "Error printing jemalloc statistics: {}", err);
+        }
+    }
+}
+
+/// Statistics reported by mimalloc.
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+mod native {
+    use core::ptr::{addr_of_mut, null_mut};
+    use libmimalloc_sys::{mi_process_info, mi_stats_print_out};
+
+    /// Statistics reported by the allocator, in bytes.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct NativeStats {
+        /// Resident set size, estimated from `current_commit` on Linux.
+        pub current_rss:    usize,
+        /// Peak of `current_rss`.
+        pub peak_rss:       usize,
+        /// Memory committed by the allocator.
+        pub current_commit: usize,
+        /// Peak of `current_commit`.
+        pub peak_commit:    usize,
+    }
+
+    impl NativeStats {
+        pub const ALLOCATOR: &'static str = "mimalloc";
+
+        /// Statistics by name.
+        #[must_use]
+        pub const fn fields(&self) -> [(&'static str, usize); 4] {
+            [
+                ("current_rss", self.current_rss),
+                ("peak_rss", self.peak_rss),
+                ("current_commit", self.current_commit),
+                ("peak_commit", self.peak_commit),
+            ]
+        }
+    }
+
+    /// Read the current allocator statistics.
+    ///
+    /// The `mi_stats_*` functions only print their statistics as text, so the
+    /// numbers come from `mi_process_info`.
+    #[must_use]
+    pub fn native_stats() -> NativeStats {
+        let mut stats = NativeStats::default();
+        let (mut elapsed, mut user, mut system, mut page_faults) = (0, 0, 0, 0);
+        // SAFETY: All pointers are valid for writes.
+        #[allow(unsafe_code)]
+        unsafe {
+            mi_process_info(
+                addr_of_mut!(elapsed),
+                addr_of_mut!(user),
+                addr_of_mut!(system),
+                addr_of_mut!(stats.current_rss),
+                addr_of_mut!(stats.peak_rss),
+                addr_of_mut!(stats.current_commit),
+                addr_of_mut!(stats.peak_commit),
+                addr_of_mut!(page_faults),
+            );
+        }
+        stats
+    }
+
+    pub fn print_stats() {
+        // SAFETY: Without an output function mimalloc prints to stderr.
+        #[allow(unsafe_code)]
+        unsafe {
+            mi_stats_print_out(None, null_mut());
+        }
+    }
+}
+
+#[cfg(all(
+    not(any(feature = "mimalloc", feature = "jemalloc")),
+    feature = "metered-allocator"
+))]
+mod native {
+    pub const fn print_stats() {}
+}
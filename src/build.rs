@@ -13,6 +13,8 @@ use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
 /// * `COMMIT_SHA`: The commit hash.
 /// * `COMMIT_DATE`: The commit date.
 /// * `BUILD_DATE`: The current date.
+/// * `BUILD_PROFILE`: The build profile, `debug` or `release`.
+/// * `RUSTC_VERSION`: The compiler version.
 ///
 /// # Errors
 ///
@@ -55,6 +57,18 @@ pub fn build_rs() -> Result<()> {
         "cargo:rustc-env=TARGET={}",
         var("TARGET").wrap_err("Fetching environment variable TARGET")?
     );
+    println!(
+        "cargo:rustc-env=BUILD_PROFILE={}",
+        var("PROFILE").wrap_err("Fetching environment variable PROFILE")?
+    );
+    let rustc = var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    println!(
+        "cargo:rustc-env=RUSTC_VERSION={}",
+        env_or_cmd("RUSTC_VERSION", &[&rustc, "--version"]).unwrap_or_else(|e| {
+            eprintln!("Warning: {e}");
+            "unknown".to_string()
+        })
+    );
 
     Ok(())
 }
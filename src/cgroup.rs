@@ -0,0 +1,135 @@
+//! Resource limits of this process from Linux control groups.
+//!
+//! Both the unified v2 hierarchy and v1 controllers are supported. On other
+//! platforms no limits are found.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread::available_parallelism,
+};
+
+const ROOT: &str = "/sys/fs/cgroup";
+
+/// Read a file of this process' cgroup for `controller`, or from the v2
+/// hierarchy if `controller` is empty. Falls back to the root, which is the
+/// process' own cgroup as seen from within a container.
+pub fn read(controller: &str, file: &str) -> Option<String> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let root = Path::new(ROOT);
+    let mut dirs = Vec::new();
+    for line in cgroups.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let path = path.trim_start_matches('/');
+        if controller.is_empty() && controllers.is_empty() {
+            dirs.push(root.join(path));
+        } else if !controller.is_empty() && controllers.split(',').any(|c| c == controller) {
+            dirs.push(root.join(controllers).join(path));
+            dirs.push(root.join(controller).join(path));
+        }
+    }
+    dirs.push(if controller.is_empty() {
+        root.to_path_buf()
+    } else {
+        root.join(controller)
+    });
+    dirs.into_iter()
+        .find_map(|dir: PathBuf| fs::read_to_string(dir.join(file)).ok())
+}
+
+/// Number of CPUs of the host.
+pub fn host_cpus() -> usize {
+    fs::read_to_string("/sys/devices/system/cpu/online")
+        .ok()
+        .and_then(|list| parse_cpu_list(&list))
+        .or_else(|| available_parallelism().ok().map(usize::from))
+        .unwrap_or(1)
+}
+
+/// Number of CPUs this process can use, taking the CPU affinity, cgroup
+/// cpuset and cgroup CPU quota into account. Quotas are rounded up.
+pub fn effective_cpus() -> usize {
+    let affinity = available_parallelism().map_or(usize::MAX, usize::from);
+    let cpuset = read("", "cpuset.cpus.effective")
+        .or_else(|| read("cpuset", "cpuset.effective_cpus"))
+        .or_else(|| read("cpuset", "cpuset.cpus"))
+        .and_then(|list| parse_cpu_list(&list))
+        .unwrap_or(usize::MAX);
+    let quota = read("", "cpu.max")
+        .and_then(|max| parse_cpu_max(&max))
+        .or_else(|| {
+            parse_cfs_quota(
+                &read("cpu", "cpu.cfs_quota_us")?,
+                &read("cpu", "cpu.cfs_period_us")?,
+            )
+        })
+        .unwrap_or(usize::MAX);
+    affinity.min(cpuset).min(quota).clamp(1, host_cpus().max(1))
+}
+
+/// Count the CPUs in a list like `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Option<usize> {
+    let list = list.trim();
+    if list.is_empty() {
+        return None;
+    }
+    list.split(',').try_fold(0, |count, range| {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let (start, end): (usize, usize) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+        Some(count + end.checked_sub(start)? + 1)
+    })
+}
+
+/// CPUs allowed by a cgroup v2 `cpu.max` file, e.g. `150000 100000`.
+fn parse_cpu_max(max: &str) -> Option<usize> {
+    let (quota, period) = max.trim().split_once(' ')?;
+    cpus_from_quota(quota.parse().ok()?, period.parse().ok()?)
+}
+
+/// CPUs allowed by cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us`
+/// files. The quota is `-1` if there is none.
+fn parse_cfs_quota(quota: &str, period: &str) -> Option<usize> {
+    let quota: i64 = quota.trim().parse().ok()?;
+    cpus_from_quota(quota.try_into().ok()?, period.trim().parse().ok()?)
+}
+
+fn cpus_from_quota(quota: u64, period: u64) -> Option<usize> {
+    if quota == 0 || period == 0 {
+        return None;
+    }
+    quota.div_ceil(period).try_into().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0\n"), Some(1));
+        assert_eq!(parse_cpu_list("0-63\n"), Some(64));
+        assert_eq!(parse_cpu_list("0-3,8,10-11"), Some(7));
+        assert_eq!(parse_cpu_list(""), None);
+        assert_eq!(parse_cpu_list("3-1"), None);
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(2));
+        assert_eq!(parse_cpu_max("400000 100000\n"), Some(4));
+        assert_eq!(parse_cfs_quota("-1\n", "100000\n"), None);
+        assert_eq!(parse_cfs_quota("50000\n", "100000\n"), Some(1));
+    }
+
+    #[test]
+    fn test_effective_cpus() {
+        let cpus = effective_cpus();
+        assert!(cpus >= 1);
+        assert!(cpus <= host_cpus());
+    }
+}
@@ -0,0 +1,239 @@
+//! Crash reports written on panics and fatal errors.
+//!
+//! With `--crash-dir` a report with the error, span trace, backtrace, version,
+//! options, recent log lines and system information is written for daemons
+//! where nobody sees the terminal output.
+
+use crate::{
+    cgroup, default_from_clap, repro,
+    trace::{self, ErrorReport},
+    Version,
+};
+use chrono::{SecondsFormat, Utc};
+use clap::Parser;
+use eyre::Report;
+use once_cell::sync::OnceCell;
+use std::{
+    backtrace::Backtrace,
+    fs,
+    panic::Location,
+    path::PathBuf,
+    process::id as pid,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::SystemTime,
+};
+use tracing::{error, info};
+use tracing_error::SpanTrace;
+use users::{get_current_gid, get_current_uid};
+
+static CRASH: OnceCell<Crash> = OnceCell::new();
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Directory to write a crash report to when the program panics or fails.
+    #[clap(long, env)]
+    crash_dir: Option<PathBuf>,
+
+    /// Number of recent log lines to include in crash reports.
+    #[clap(long, env, default_value = "100")]
+    crash_log_lines: usize,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Enable crash reports. Must be called before the log system is
+    /// initialized to record log lines.
+    pub fn init(self, version: &Version) {
+        let Some(dir) = self.crash_dir else {
+            return;
+        };
+        trace::buffer_logs(self.crash_log_lines);
+        let _ = CRASH.set(Crash {
+            dir,
+            version: version.clone(),
+        });
+    }
+}
+
+struct Crash {
+    dir:     PathBuf,
+    version: Version,
+}
+
+/// Write a crash report for a panic.
+pub fn on_panic(thread: &str, location: Option<&Location<'_>>, message: &str) {
+    if CRASH.get().is_none() {
+        return;
+    }
+    let location = location.map_or_else(String::new, |location| format!(" at {location}"));
+    write_report("Panic", &[
+        format!("Thread '{thread}' panicked{location}: {message}"),
+        String::new(),
+        format!("Span trace:\n{}", SpanTrace::capture()),
+        String::new(),
+        format!("Backtrace:\n{}", Backtrace::force_capture()),
+    ]);
+    PANICKED.store(true, Ordering::Relaxed);
+}
+
+/// Write a crash report for the error the program failed with.
+///
+/// Skipped if a panic report was written, as the error then usually is the
+/// panic, e.g. when main panicked.
+pub fn on_failure(report: &Report) {
+    if CRASH.get().is_none() || PANICKED.load(Ordering::Relaxed) {
+        return;
+    }
+    let report = ErrorReport::new(report);
+    let location = |file: Option<&str>, line: Option<u32>| match (file, line) {
+        (Some(file), Some(line)) => format!("\n        at {file}:{line}"),
+        (Some(file), None) => format!("\n        at {file}"),
+        _ => String::new(),
+    };
+    let mut lines = Vec::new();
+    for (i, cause) in std::iter::once(&report.message).chain(&report.causes).enumerate() {
+        lines.push(format!("  {i}: {cause}"));
+    }
+    lines.extend(report.notes.iter().map(|note| format!("  {note}")));
+    lines.push(String::new());
+    lines.push("Span trace:".to_owned());
+    for (i, span) in report.span_trace.iter().enumerate() {
+        lines.push(format!(
+            "  {i}: {}::{} with {}{}",
+            span.target,
+            span.name,
+            span.fields,
+            location(span.file, span.line)
+        ));
+    }
+    lines.push(String::new());
+    lines.push("Backtrace:".to_owned());
+    for (i, frame) in report.backtrace.iter().enumerate() {
+        lines.push(format!(
+            "  {i}: {}{}",
+            frame.function.as_deref().unwrap_or("<unknown>"),
+            location(frame.file.as_deref(), frame.line)
+        ));
+    }
+    write_report("Error", &lines);
+}
+
+fn write_report(kind: &str, error: &[String]) {
+    let Some(crash) = CRASH.get() else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let path = crash
+        .dir
+        .join(format!("crash-{}-{}-{}.txt", pid(), timestamp, count));
+    match repro::write(&path, &crash.report(kind, error)) {
+        Ok(()) => info!(path = %path.display(), "Crash report written"),
+        Err(err) => error!(?err, "Error writing crash report: {}", err),
+    }
+}
+
+impl Crash {
+    fn report(&self, kind: &str, error: &[String]) -> String {
+        let version = &self.version;
+        let mut sections = vec![
+            format!(
+                "Crash report for {} {}\nTime: {}",
+                version.pkg_name,
+                version.pkg_version,
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+            format!("{kind}:\n{}", error.join("\n")),
+            format!("Version:\n{}", indent(version.long_version)),
+            format!("System:\n{}", indent(&system_info(version).join("\n"))),
+        ];
+        let summary = repro::summary();
+        if !summary.is_empty() {
+            sections.push(summary.join("\n"));
+        }
+        sections.push(format!(
+            "Recent log lines:\n{}",
+            indent(&trace::recent_logs().join("\n"))
+        ));
+        sections.join("\n\n") + "\n"
+    }
+}
+
+fn system_info(version: &Version) -> Vec<String> {
+    let read = |path: &str| fs::read_to_string(path).map(|s| s.trim().to_owned()).ok();
+    let resident = read("/proc/self/status").and_then(|status| {
+        status
+            .lines()
+            .find_map(|line| Some(line.strip_prefix("VmRSS:")?.trim().to_owned()))
+    });
+    let unknown = || "unknown".to_owned();
+    vec![
+        format!("Host:         {}", read("/proc/sys/kernel/hostname").unwrap_or_else(unknown)),
+        format!("Kernel:       {}", read("/proc/sys/kernel/osrelease").unwrap_or_else(unknown)),
+        format!("Target:       {}", version.target),
+        format!("Commit:       {}", version.commit_hash),
+        format!("Rustc:        {}", version.rustc),
+        format!("Profile:      {}", version.profile),
+        format!("PID:          {}", pid()),
+        format!("UID:          {}", get_current_uid()),
+        format!("GID:          {}", get_current_gid()),
+        format!(
+            "Cores:        {} ({} effective)",
+            cgroup::host_cpus(),
+            cgroup::effective_cpus()
+        ),
+        format!("Resident:     {}", resident.unwrap_or_else(unknown)),
+        format!(
+            "Memory limit: {}",
+            cgroup::read("", "memory.max").map_or_else(unknown, |max| max.trim().to_owned())
+        ),
+    ]
+}
+
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("  {line}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use eyre::eyre;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_report() {
+        let crash = Crash {
+            dir:     PathBuf::new(),
+            version: Version::test(),
+        };
+        let report = crash.report("Error", &["Error fetching config".to_owned()]);
+        assert!(report.starts_with("Crash report for cli-test v0.0.0\nTime: "));
+        assert!(report.contains("\n\nError:\nError fetching config\n\n"));
+        assert!(report.contains("\n\nVersion:\n  v0.0.0\n  First release\n\n"));
+        assert!(report.contains("\n  Target:       aarch64-apple-darwin\n"));
+        assert!(report.contains("\n\nRecent log lines:\n"));
+    }
+
+    #[test]
+    fn test_one_report_per_panic() {
+        let dir = TempDir::new().unwrap();
+        let _ = CRASH.set(Crash {
+            dir:     dir.path().to_owned(),
+            version: Version::test(),
+        });
+        on_panic("main", None, "Test panic");
+        on_failure(&eyre!("Main panicked: Test panic"));
+        let reports = fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(reports, 1);
+    }
+}
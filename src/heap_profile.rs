@@ -0,0 +1,305 @@
+#![cfg(feature = "metered-allocator")]
+// Profiles can only be retrieved through the metrics server or a signal.
+#![cfg_attr(
+    not(any(feature = "prometheus", all(unix, feature = "signals"))),
+    allow(dead_code)
+)]
+//! Sampled heap profiling.
+//!
+//! Roughly every `--heap-profile-rate` bytes allocated on a thread, the
+//! allocation that crosses the threshold has its stack trace recorded. Samples
+//! are kept until the allocation is freed, so the set of samples approximates
+//! the live heap.
+//!
+//! Sampling runs inside the global allocator. Unsampled allocations only
+//! decrement a thread local counter. A sample locks the sample table and
+//! captures up to 64 stack frames, which takes a few microseconds, but happens
+//! at most once per `--heap-profile-rate` bytes allocated per thread. At the
+//! suggested rate of 512 KiB this is negligible next to the cost of producing
+//! that much memory. Frees only take the lock while samples are live.
+//!
+//! Allocations made by the profiler itself, such as growing the sample table,
+//! are not sampled, so it never waits on a lock it already holds. The stack
+//! capture takes the unwinder's locks however, so an allocation made while
+//! the thread already holds one of those, for example from a
+//! `dl_iterate_phdr` callback, could deadlock when it is sampled. Symbols are
+//! resolved when a profile is requested, outside of the allocator.
+
+use crate::default_from_clap;
+use clap::Parser;
+use core::{
+    cell::Cell,
+    ffi::c_void,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+use tracing::info;
+
+/// Maximum number of stack frames recorded per sample.
+const MAX_DEPTH: usize = 64;
+
+/// Sampling period in bytes, zero when disabled.
+static RATE: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of entries in `SAMPLES`, to skip the lock on `dealloc` when empty.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sampled live allocations by address.
+static SAMPLES: Mutex<BTreeMap<usize, Sample>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// Set while this thread is inside the profiler, to avoid recursion.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+
+    /// Bytes left to allocate on this thread until the next sample.
+    static COUNTDOWN: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Record the stack trace of one allocation per this many bytes allocated,
+    /// for heap profiles on `/debug/pprof/heap` or SIGUSR1. Zero disables heap
+    /// profiling. A good value is 524288.
+    #[clap(long, env, default_value = "0")]
+    heap_profile_rate: usize,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    pub fn init(self) {
+        if self.heap_profile_rate > 0 {
+            info!(rate = self.heap_profile_rate, "Heap profiling enabled");
+        }
+        RATE.store(self.heap_profile_rate, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    /// Estimated number of live bytes this sample represents.
+    weight: usize,
+    depth:  usize,
+    stack:  [usize; MAX_DEPTH],
+}
+
+/// Marks the current thread as inside the profiler.
+struct Busy;
+
+impl Busy {
+    fn enter() -> Option<Self> {
+        BUSY.try_with(|busy| (!busy.replace(true)).then(|| Self))
+            .ok()
+            .flatten()
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let _ = BUSY.try_with(|busy| busy.set(false));
+    }
+}
+
+fn samples() -> MutexGuard<'static, BTreeMap<usize, Sample>> {
+    SAMPLES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Called by the allocator after a successful allocation.
+pub fn on_alloc(ptr: *mut u8, size: usize) {
+    let rate = RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return;
+    }
+    let sample = COUNTDOWN.try_with(|countdown| {
+        let remaining = countdown.get();
+        if size < remaining {
+            countdown.set(remaining - size);
+            false
+        } else {
+            countdown.set(rate);
+            true
+        }
+    });
+    if sample != Ok(true) {
+        return;
+    }
+    let Some(_busy) = Busy::enter() else {
+        return;
+    };
+
+    let mut sample = Sample {
+        weight: size.max(rate),
+        depth:  0,
+        stack:  [0; MAX_DEPTH],
+    };
+    backtrace::trace(|frame| {
+        sample.stack[sample.depth] = frame.ip() as usize;
+        sample.depth += 1;
+        sample.depth < MAX_DEPTH
+    });
+    if samples().insert(ptr as usize, sample).is_none() {
+        LIVE.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Called by the allocator before deallocating.
+pub fn on_dealloc(ptr: *mut u8) {
+    if LIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let Some(_busy) = Busy::enter() else {
+        return;
+    };
+    if samples().remove(&(ptr as usize)).is_some() {
+        LIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Is heap profiling enabled?
+pub fn is_enabled() -> bool {
+    RATE.load(Ordering::Relaxed) > 0
+}
+
+/// Heap profile of the sampled live allocations in folded stack format, one
+/// `outer;...;inner bytes` line per unique stack.
+pub fn folded() -> String {
+    // Copy samples out so we don't hold the lock while resolving symbols.
+    let samples = {
+        let _busy = Busy::enter();
+        samples().values().copied().collect::<Vec<_>>()
+    };
+
+    let mut stacks = HashMap::<&[usize], usize>::new();
+    for sample in &samples {
+        *stacks.entry(&sample.stack[..sample.depth]).or_default() += sample.weight;
+    }
+
+    let mut symbols = HashMap::<usize, Vec<String>>::new();
+    let mut result = String::new();
+    for (stack, bytes) in stacks {
+        // Innermost first, including inlined frames.
+        let frames = stack
+            .iter()
+            .flat_map(|&ip| {
+                symbols
+                    .entry(ip)
+                    .or_insert_with(|| resolve(ip))
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+
+        // Drop the frames inside the allocator and profiler.
+        let start = frames
+            .iter()
+            .rposition(|name| name.contains("__rust_alloc") || name.contains("__rust_realloc"))
+            .map_or(0, |i| i + 1);
+        let frames = frames[start..]
+            .iter()
+            .filter(|name| {
+                !name.contains("heap_profile::on_alloc") && !name.starts_with("backtrace::")
+            })
+            .rev()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        writeln!(result, "{} {}", frames.join(";"), bytes).unwrap();
+    }
+    result
+}
+
+fn resolve(ip: usize) -> Vec<String> {
+    let mut names = vec![];
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        names.push(symbol.name().map_or_else(
+            || format!("{ip:#x}"),
+            |name| format!("{name:#}"),
+        ));
+    });
+    if names.is_empty() {
+        names.push(format!("{ip:#x}"));
+    }
+    names
+}
+
+/// Write a heap profile to the temp directory on SIGUSR1.
+#[cfg(all(unix, feature = "signals"))]
+pub fn watch_signal() {
+    use crate::shutdown::await_shutdown;
+    use std::{env::temp_dir, fs, process::id as pid, time::SystemTime};
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::error;
+
+    tokio::spawn(async {
+        let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+            Ok(signal) => signal,
+            Err(err) => {
+                error!(?err, "Error handling SIGUSR1: {}", err);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                () = await_shutdown() => break,
+                _ = sigusr1.recv() => {},
+            };
+            if !is_enabled() {
+                info!("SIGUSR1 received, but heap profiling is disabled");
+                continue;
+            }
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let path = temp_dir().join(format!("heap-{}-{}.folded", pid(), timestamp));
+            let profile = match tokio::task::spawn_blocking(folded).await {
+                Ok(profile) => profile,
+                Err(err) => {
+                    error!(?err, "Error creating heap profile: {}", err);
+                    continue;
+                }
+            };
+            match fs::write(&path, profile) {
+                Ok(()) => info!(path = %path.display(), "Heap profile written"),
+                Err(err) => error!(?err, "Error writing heap profile: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn allocate_here(ptr: usize) {
+        on_alloc(ptr as *mut u8, 100);
+    }
+
+    #[test]
+    fn test_heap_profile() {
+        // Fake addresses that can't collide with real allocations.
+        let ptr = usize::MAX - 4095;
+
+        Options {
+            heap_profile_rate: 1,
+        }
+        .init();
+        allocate_here(ptr);
+        RATE.store(0, Ordering::Relaxed);
+        assert!(samples().contains_key(&ptr));
+        let profile = folded();
+        let line = profile
+            .lines()
+            .find(|line| line.contains("allocate_here"))
+            .unwrap();
+        assert!(line.contains("test_heap_profile"));
+        assert!(line.ends_with(" 100"));
+
+        on_dealloc(ptr as *mut u8);
+        assert!(!samples().contains_key(&ptr));
+    }
+}
@@ -1,15 +1,29 @@
 use crate::shutdown::await_shutdown;
+use once_cell::sync::Lazy;
 use std::time::{Duration, Instant};
 use tokio::time::{interval, MissedTickBehavior};
 use tracing::info;
 
-pub async fn heartbeat() {
-    let start = Instant::now();
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Start measuring the uptime.
+pub fn start_uptime() {
+    Lazy::force(&START);
+}
+
+/// Time elapsed since the program started.
+pub fn uptime() -> Duration {
+    START.elapsed()
+}
 
+pub async fn heartbeat() {
     let mut interval = interval(Duration::from_secs(5 * 60));
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     interval.reset(); // Skip immediate first tick
 
+    #[cfg(feature = "rayon")]
+    let mut rayon_stats = (Instant::now(), crate::rayon::rayon_stats());
+
     loop {
         tokio::select! {
             _ = await_shutdown() => break,
@@ -17,9 +31,43 @@ pub async fn heartbeat() {
         };
 
         // Measure uptime
-        let uptime = start.elapsed();
+        let uptime = uptime();
+
+        // Fraction of time Rayon threads were busy with tasks started with the
+        // helpers since the last heartbeat
+        #[cfg(feature = "rayon")]
+        let rayon_task_utilization = {
+            let (last, last_stats) =
+                std::mem::replace(&mut rayon_stats, (Instant::now(), crate::rayon::rayon_stats()));
+            rayon_stats.1.utilization(&last_stats, last.elapsed())
+        };
+        #[cfg(not(feature = "rayon"))]
+        let rayon_task_utilization: Option<f64> = None;
+
+        // Include allocator statistics
+        #[cfg(any(feature = "mimalloc", feature = "jemalloc"))]
+        let stats = crate::allocator::native_stats();
+        #[cfg(feature = "jemalloc")]
+        let (allocated, resident, retained) =
+            (Some(stats.allocated), Some(stats.resident), Some(stats.retained));
+        #[cfg(not(feature = "jemalloc"))]
+        let (allocated, resident, retained): (Option<usize>, Option<usize>, Option<usize>) =
+            (None, None, None);
+        #[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+        let (rss, commit) = (Some(stats.current_rss), Some(stats.current_commit));
+        #[cfg(not(all(feature = "mimalloc", not(feature = "jemalloc"))))]
+        let (rss, commit): (Option<usize>, Option<usize>) = (None, None);
 
-        info!(?uptime, "Heartbeat");
+        info!(
+            ?uptime,
+            allocated,
+            resident,
+            retained,
+            rss,
+            commit,
+            rayon_task_utilization,
+            "Heartbeat"
+        );
 
         // FEATURE: Log Tokio metrics once API is available.
     }
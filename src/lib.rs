@@ -9,12 +9,19 @@
 
 mod allocator;
 mod build;
+mod cgroup;
+mod crash;
+mod heap_profile;
 mod heartbeat;
+mod memory_limit;
 mod metered_allocator;
+mod panic;
 mod prometheus;
 mod rand;
 mod rayon;
+mod repro;
 mod shutdown;
+mod size;
 mod trace;
 mod version;
 
@@ -25,8 +32,9 @@ pub use crate::{
     version::Version,
 };
 use clap::{Args, CommandFactory, FromArgMatches, Parser};
-use eyre::{Error as EyreError, Report, Result as EyreResult, WrapErr};
-use std::{future::Future, ptr::addr_of};
+use eyre::{eyre, Error as EyreError, Report, Result as EyreResult, WrapErr};
+use futures::FutureExt;
+use std::{future::Future, panic::AssertUnwindSafe, ptr::addr_of};
 use tokio::runtime;
 use tracing::{error, info};
 
@@ -36,6 +44,27 @@ pub use crate::shutdown::reset_shutdown;
 #[cfg(feature = "metered-allocator")]
 use crate::metered_allocator::MeteredAllocator;
 
+#[cfg(feature = "metered-allocator")]
+pub use crate::memory_limit::on_soft_memory_limit;
+
+#[cfg(any(feature = "mimalloc", feature = "jemalloc"))]
+pub use crate::allocator::{native_stats, NativeStats};
+
+#[cfg(feature = "metered-allocator")]
+pub use crate::metered_allocator::{
+    allocator_stats, kind_totals, reset_peak_bytes, thread_allocations, AllocatorStats,
+    KindTotals, ThreadAllocations, ThreadKind,
+};
+
+#[cfg(feature = "rand")]
+pub use crate::rand::{fork_rng, random_seed, rng, with_thread_rng, SharedRng};
+
+#[cfg(feature = "rayon")]
+pub use crate::rayon::{
+    rayon_join, rayon_scope, rayon_spawn, rayon_stats, spawn_compute, with_current_span,
+    RayonStats, Scope,
+};
+
 #[cfg(feature = "opentelemetry")]
 pub use crate::trace::{trace_from_headers, trace_to_headers};
 
@@ -69,10 +98,35 @@ struct Options<O: Args> {
     #[clap(flatten)]
     rayon: rayon::Options,
 
+    #[clap(flatten)]
+    repro: repro::Options,
+
+    #[clap(flatten)]
+    panic: panic::Options,
+
+    #[clap(flatten)]
+    crash: crash::Options,
+
     #[cfg(feature = "prometheus")]
     #[clap(flatten)]
     prometheus: prometheus::Options,
 
+    #[cfg(feature = "metered-allocator")]
+    #[clap(flatten)]
+    heap_profile: heap_profile::Options,
+
+    #[cfg(feature = "metered-allocator")]
+    #[clap(flatten)]
+    memory_limit: memory_limit::Options,
+
+    #[cfg(any(
+        feature = "mimalloc",
+        feature = "jemalloc",
+        feature = "metered-allocator"
+    ))]
+    #[clap(flatten)]
+    allocator: allocator::Options,
+
     #[clap(flatten)]
     app: O,
 }
@@ -86,9 +140,27 @@ where
     F: Future<Output = Result<(), E>>,
     E: Into<Report> + Send + Sync + 'static,
 {
-    if let Err(report) = run_fallible(&version, app) {
-        error!(?report, "{}", report);
+    // Measure uptime from the start
+    heartbeat::start_uptime();
+
+    // Buffer log events until the log system is initialized
+    trace::buffer_early_logs();
+
+    let result = run_fallible(&version, app);
+
+    #[cfg(any(
+        feature = "mimalloc",
+        feature = "jemalloc",
+        feature = "metered-allocator"
+    ))]
+    allocator::print_stats_on_exit();
+
+    if let Err(report) = result {
+        trace::log_report(&report);
+        repro::on_failure(&version, &report);
+        crash::on_failure(&report);
         error!("Program terminating abnormally");
+        trace::flush_early_logs();
         std::process::exit(1);
     }
 }
@@ -101,40 +173,62 @@ where
     E: Into<Report> + Send + Sync + 'static,
 {
     // Install panic handler
-    // TODO: write panics to log, like Err results.
-    color_eyre::config::HookBuilder::default()
-        .issue_url(format!("{}/issues/new", version.pkg_repo))
-        .add_issue_metadata(
-            "version",
-            format!("{} {}", version.pkg_name, version.long_version),
-        )
-        .install()
-        .map_err(|err| {
-            eprintln!("Error: {}", err);
-            err
-        })?;
+    panic::install(version)?;
 
     // Parse CLI and handle help and version (which will stop the application).
-    let matches = Options::<O>::command()
+    let command = Options::<O>::command()
         .name(version.pkg_name)
         .version(version.pkg_version)
-        .long_version(version.long_version)
-        .get_matches();
+        .long_version(version.long_version);
+    let matches = match command.clone().try_get_matches() {
+        Ok(matches) => matches,
+        // Help and version are printed to stdout
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            return Err(eyre!("{}", err.to_string().trim_end())).wrap_err("Error parsing arguments")
+        }
+    };
+
+    let options = Options::<O>::from_arg_matches(&matches).wrap_err("Error parsing arguments")?;
+    options.tracing.set_early_output(version);
 
-    let options = Options::<O>::from_arg_matches(&matches)?;
+    // Record the invocation for repro bundles
+    options.repro.clone().init(&command, &matches);
+    options.panic.init();
+    options.crash.clone().init(version);
 
     // Start allocator metering (if enabled)
     allocator::start_metering();
-
-    // TODO: Early logging to catch errors before we start the runtime.
+    #[cfg(any(
+        feature = "mimalloc",
+        feature = "jemalloc",
+        feature = "metered-allocator"
+    ))]
+    options.allocator.init();
 
     // Launch Tokio runtime
-    // TODO: https://docs.rs/tokio/latest/tokio/runtime/struct.Builder.html#method.unhandled_panic
-    runtime::Builder::new_multi_thread()
-        .enable_all()
+    let mut runtime = runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    #[cfg(tokio_unstable)]
+    options.panic.configure_runtime(&mut runtime);
+    if std::env::var_os("TOKIO_WORKER_THREADS").is_none() {
+        runtime.worker_threads(cgroup::effective_cpus());
+    }
+    // Attribute allocations to worker or blocking threads. Only workers park.
+    #[cfg(feature = "metered-allocator")]
+    runtime
+        .on_thread_start(|| metered_allocator::set_thread_kind(ThreadKind::Blocking))
+        .on_thread_park(|| metered_allocator::set_thread_kind(ThreadKind::Tokio))
+        .on_thread_stop(metered_allocator::flush_thread);
+    runtime
         .build()
         .wrap_err("Error creating Tokio runtime")?
         .block_on(async {
+            // Start log system. Events from other threads are only logged after
+            // this, so tasks are started below.
+            let load_addr = addr_of!(app) as usize;
+            options.tracing.init(version, load_addr)?;
+
             // Start heartbeat
             let heartbeat = tokio::spawn(heartbeat());
 
@@ -142,32 +236,51 @@ where
             #[cfg(feature = "signals")]
             shutdown::watch_signals();
 
-            // Start log system
-            let load_addr = addr_of!(app) as usize;
-            options.tracing.init(version, load_addr).map_err(|err| {
-                eprintln!("Error: {}", err);
-                err
-            })?;
-
             #[cfg(feature = "rand")]
             options.rand.init();
 
             #[cfg(feature = "rayon")]
             options.rayon.init()?;
 
+            #[cfg(feature = "metered-allocator")]
+            options.heap_profile.init();
+
+            #[cfg(feature = "metered-allocator")]
+            options.memory_limit.init()?;
+
+            // Dump heap profiles on SIGUSR1
+            #[cfg(all(unix, feature = "metered-allocator", feature = "signals"))]
+            heap_profile::watch_signal();
+
+            // Log the backfill on SIGUSR2
+            #[cfg(all(unix, feature = "signals"))]
+            trace::watch_backfill_signal();
+
             // Start prometheus
             #[cfg(feature = "prometheus")]
-            let prometheus = tokio::spawn(prometheus::main(options.prometheus));
+            let prometheus = tokio::spawn(prometheus::main(options.prometheus, version.clone()));
 
-            // Start main
-            app(options.app).await.map_err(E::into)?;
+            // Start main, and shut down gracefully if it panics. The panic hook
+            // has already logged the panic.
+            let result = match AssertUnwindSafe(app(options.app)).catch_unwind().await {
+                Ok(result) => result.map_err(E::into),
+                Err(payload) => Err(eyre!("Main panicked: {}", panic::message(&*payload))),
+            };
 
             // Initiate shutdown if main returns
             shutdown::shutdown();
 
-            // Wait for prometheus to finish
+            // Wait for prometheus to finish, including the final Pushgateway push,
+            // also when main failed.
             #[cfg(feature = "prometheus")]
-            prometheus.await??;
+            let result = match (result, prometheus.await?) {
+                (Err(err), Err(prometheus)) => {
+                    error!(?prometheus, "Error in metrics server: {}", prometheus);
+                    Err(err)
+                }
+                (result, prometheus) => result.and(prometheus),
+            };
+            result?;
 
             // Submit remaining traces
             trace::shutdown()?;
@@ -178,7 +291,22 @@ where
             Result::<(), EyreError>::Ok(())
         })?;
 
+    // Fail if we shut down because of a panic
+    panic::check()?;
+
     // Terminate successfully
+    #[cfg(feature = "metered-allocator")]
+    {
+        use crate::metered_allocator::Bytes;
+        let stats = allocator_stats();
+        info!(
+            allocated = %Bytes(stats.allocated_bytes),
+            peak = %Bytes(stats.peak_bytes as u64),
+            allocations = stats.alloc_count,
+            "Program terminating normally"
+        );
+    }
+    #[cfg(not(feature = "metered-allocator"))]
     info!("Program terminating normally");
     Ok(())
 }
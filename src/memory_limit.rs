@@ -0,0 +1,236 @@
+#![cfg(feature = "metered-allocator")]
+//! Memory limit enforcement.
+//!
+//! Live memory is polled and compared to two thresholds below the limit.
+//! Above the soft limit a warning is logged and the callbacks registered with
+//! [`on_soft_memory_limit`] are called, so the application can shed load.
+//! Above the shutdown limit an error is logged and [`crate::shutdown`] is
+//! called, so the program can exit cleanly.
+//!
+//! Only allocations that would exceed the limit itself fail, as a last resort
+//! when memory grows faster than it is polled. On stable Rust this makes the
+//! default allocation error handler abort the process.
+//!
+//! Only heap allocations are counted, while the cgroup `memory.max` limits the
+//! resident memory, which also includes fragmentation, thread stacks and code.
+//! The default limit is therefore a share of `memory.max`, and only roughly
+//! tracks when the kernel would kill the process.
+
+use crate::{
+    cgroup, default_from_clap, metered_allocator, metered_allocator::allocator_stats,
+    shutdown::await_shutdown, size::parse_bytes,
+};
+use clap::Parser;
+use eyre::{ensure, Result as EyreResult};
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+/// How often live memory is compared to the soft and shutdown limits.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Percentage of the cgroup `memory.max` used as the default heap limit, to
+/// leave room for memory that is not allocated on the heap.
+const CGROUP_HEAP_SHARE: usize = 80;
+
+type Callback = Arc<dyn Fn() + Send + Sync>;
+
+static CALLBACKS: Mutex<Vec<Callback>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+#[allow(clippy::struct_field_names)] // The names are the argument names.
+pub struct Options {
+    /// Maximum heap size, e.g. `512M` or `2G`. Allocations beyond this fail,
+    /// which aborts the program. Defaults to 80% of the cgroup `memory.max`
+    /// limit if there is one, as that also limits memory outside the heap.
+    #[clap(long, env, value_parser = parse_bytes)]
+    memory_limit: Option<usize>,
+
+    /// Percentage of the memory limit above which a warning is logged and the
+    /// callbacks registered with `on_soft_memory_limit` are called.
+    #[clap(
+        long,
+        env,
+        default_value = "90",
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    memory_soft_limit: u8,
+
+    /// Percentage of the memory limit above which an error is logged and the
+    /// program is shut down, before allocations start failing.
+    #[clap(
+        long,
+        env,
+        default_value = "95",
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    memory_shutdown_limit: u8,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Set the allocator limit and start watching the soft and shutdown limits.
+    pub fn init(self) -> EyreResult<()> {
+        ensure!(
+            self.memory_soft_limit <= self.memory_shutdown_limit,
+            "Memory soft limit {}% is above the shutdown limit {}%",
+            self.memory_soft_limit,
+            self.memory_shutdown_limit
+        );
+        let cgroup_limit = || {
+            cgroup::read("", "memory.max")
+                .as_deref()
+                .and_then(parse_memory_max)
+                .map(|max| max / 100 * CGROUP_HEAP_SHARE)
+        };
+        let Some(limit) = self.memory_limit.or_else(cgroup_limit) else {
+            return Ok(());
+        };
+        let soft_limit = limit / 100 * usize::from(self.memory_soft_limit);
+        let shutdown_limit = limit / 100 * usize::from(self.memory_shutdown_limit);
+        info!(limit, soft_limit, shutdown_limit, "Memory limit enabled");
+        ensure!(
+            allocator_stats().live_bytes < limit,
+            "Memory limit {} is below current usage",
+            limit
+        );
+        metered_allocator::set_memory_limit(limit);
+        tokio::spawn(watch(
+            || allocator_stats().live_bytes,
+            soft_limit,
+            shutdown_limit,
+            crate::shutdown,
+        ));
+        Ok(())
+    }
+}
+
+/// Register a callback for when memory usage exceeds the soft limit.
+///
+/// Callbacks are called from a Tokio task each time usage rises above the
+/// soft limit, and should quickly free memory or stop accepting work.
+pub fn on_soft_memory_limit<F>(callback: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    CALLBACKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Arc::new(callback));
+}
+
+/// Call the soft limit callbacks, without holding the lock so they can
+/// register more.
+fn call_callbacks() {
+    let callbacks = CALLBACKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    for callback in callbacks {
+        callback();
+    }
+}
+
+/// Poll `live_bytes` and act on crossing the soft and shutdown limits.
+async fn watch(
+    live_bytes: impl Fn() -> usize,
+    soft_limit: usize,
+    shutdown_limit: usize,
+    shutdown: impl FnOnce(),
+) {
+    let mut interval = interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut exceeded = false;
+    loop {
+        tokio::select! {
+            () = await_shutdown() => break,
+            _ = interval.tick() => {},
+        };
+        let live_bytes = live_bytes();
+        if live_bytes > shutdown_limit {
+            error!(live_bytes, shutdown_limit, "Memory usage above shutdown limit");
+            shutdown();
+            break;
+        }
+        match (exceeded, live_bytes > soft_limit) {
+            (false, true) => {
+                warn!(live_bytes, soft_limit, "Memory usage above soft limit");
+                call_callbacks();
+            }
+            (true, false) => info!(live_bytes, soft_limit, "Memory usage below soft limit"),
+            _ => {}
+        }
+        exceeded = live_bytes > soft_limit;
+    }
+}
+
+/// Parse the contents of a cgroup v2 `memory.max` file.
+fn parse_memory_max(contents: &str) -> Option<usize> {
+    contents.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory_max() {
+        assert_eq!(parse_memory_max("max\n"), None);
+        assert_eq!(parse_memory_max("536870912\n"), Some(512 << 20));
+    }
+
+    #[test]
+    fn test_callback_registers_callback() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        on_soft_memory_limit(|| {
+            if CALLS.fetch_add(1, Ordering::Relaxed) == 0 {
+                on_soft_memory_limit(|| {});
+            }
+        });
+        call_callbacks();
+        assert!(CALLS.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use tokio::time::{sleep, timeout};
+        static LIVE: AtomicUsize = AtomicUsize::new(0);
+        static CALLED: AtomicBool = AtomicBool::new(false);
+        let shut_down = Arc::new(AtomicBool::new(false));
+        on_soft_memory_limit(|| {
+            if LIVE.load(Ordering::Relaxed) > 100 {
+                CALLED.store(true, Ordering::Relaxed);
+            }
+        });
+        let watcher = tokio::spawn(watch(|| LIVE.load(Ordering::Relaxed), 100, 200, {
+            let shut_down = shut_down.clone();
+            move || shut_down.store(true, Ordering::Relaxed)
+        }));
+
+        // Above the soft limit the callbacks are called
+        LIVE.store(150, Ordering::Relaxed);
+        timeout(Duration::from_secs(5), async {
+            while !CALLED.load(Ordering::Relaxed) {
+                sleep(POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!shut_down.load(Ordering::Relaxed));
+
+        // Above the shutdown limit shutdown starts and the watcher stops
+        LIVE.store(250, Ordering::Relaxed);
+        timeout(Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(shut_down.load(Ordering::Relaxed));
+    }
+}
@@ -1,63 +1,428 @@
 #![cfg(feature = "metered-allocator")]
+//! Allocation metering.
+//!
+//! To avoid contention between threads, allocations are counted in thread
+//! local accumulators that are flushed to the global totals every
+//! [`FLUSH_BYTES`] bytes or [`FLUSH_OPS`] operations. Totals are kept per
+//! [`ThreadKind`].
 
-use core::sync::atomic::{AtomicBool, Ordering};
-use once_cell::sync::Lazy;
-use prometheus::{
-    exponential_buckets, register_histogram, register_int_counter, Histogram, IntCounter,
+use crate::heap_profile;
+use core::{
+    cell::RefCell,
+    ptr::null_mut,
+    sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+};
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    fmt::{self, Display, Formatter},
 };
-use std::alloc::{GlobalAlloc, Layout};
 
 pub use std::alloc::System as StdAlloc;
 
-static ALLOCATED: Lazy<IntCounter> =
-    Lazy::new(|| register_int_counter!("mem_alloc", "Cumulative memory allocated.").unwrap());
-static FREED: Lazy<IntCounter> =
-    Lazy::new(|| register_int_counter!("mem_free", "Cumulative memory freed.").unwrap());
-static SIZE: Lazy<Histogram> = Lazy::new(|| {
-    register_histogram!(
-        "mem_alloc_size",
-        "Distribution of allocation sizes.",
-        exponential_buckets(16.0, 4.0, 10).unwrap()
-    )
-    .unwrap()
-});
+/// Pending bytes allocated plus freed after which a thread flushes.
+const FLUSH_BYTES: u64 = 64 << 10;
 
-pub struct MeteredAllocator<T: GlobalAlloc> {
-    inner:    T,
-    metering: AtomicBool,
+/// Pending operations after which a thread flushes.
+const FLUSH_OPS: u64 = 256;
+
+/// Number of allocation size buckets, the last one is unbounded.
+pub const BUCKETS: usize = 11;
+
+/// Kind of thread allocations are attributed to, see [`kind_totals`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadKind {
+    /// The thread that called `run`.
+    Main,
+    /// Tokio worker threads.
+    Tokio,
+    /// Tokio blocking threads, and workers that have not parked yet.
+    Blocking,
+    /// Rayon compute threads.
+    Rayon,
+    /// All other threads.
+    Other,
 }
 
-impl<T: GlobalAlloc> MeteredAllocator<T> {
-    pub const fn new(inner: T) -> Self {
-        Self {
-            inner,
-            metering: AtomicBool::new(false),
+impl Display for ThreadKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+impl ThreadKind {
+    pub const ALL: [Self; 5] = [
+        Self::Main,
+        Self::Tokio,
+        Self::Blocking,
+        Self::Rayon,
+        Self::Other,
+    ];
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::Main => "main",
+            Self::Tokio => "tokio",
+            Self::Blocking => "blocking",
+            Self::Rayon => "rayon",
+            Self::Other => "other",
         }
     }
+}
 
-    pub fn start_metering(&self) {
-        if self.metering.load(Ordering::Acquire) {
-            return;
+/// Counts accumulated in a thread and not yet flushed.
+#[derive(Clone, Copy)]
+struct Pending {
+    allocated: u64,
+    freed:     u64,
+    allocs:    u64,
+    reallocs:  u64,
+    sizes:     [u64; BUCKETS],
+}
+
+impl Pending {
+    const EMPTY: Self = Self {
+        allocated: 0,
+        freed:     0,
+        allocs:    0,
+        reallocs:  0,
+        sizes:     [0; BUCKETS],
+    };
+
+    const fn should_flush(&self) -> bool {
+        self.allocated + self.freed >= FLUSH_BYTES || self.allocs + self.reallocs >= FLUSH_OPS
+    }
+}
+
+/// Flushed totals for one kind of thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KindTotals {
+    /// Bytes allocated, including growth by reallocation.
+    pub allocated: u64,
+    /// Bytes freed, including shrinking by reallocation.
+    pub freed:     u64,
+    /// Number of allocations, excluding reallocations.
+    pub allocs:    u64,
+    /// Number of reallocations.
+    pub reallocs:  u64,
+    /// Allocation counts by size bucket, see [`KindTotals::bucket_bound`].
+    pub sizes:     [u64; BUCKETS],
+}
+
+impl KindTotals {
+    /// Upper bound in bytes of the allocation sizes in `sizes[index]`.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub const fn bucket_bound(index: usize) -> f64 {
+        if index < BUCKETS - 1 {
+            (16_u64 << (2 * index)) as f64
+        } else {
+            f64::INFINITY
         }
-        Lazy::force(&ALLOCATED);
-        Lazy::force(&SIZE);
-        Lazy::force(&FREED);
-        self.metering.store(true, Ordering::Release);
     }
+}
 
-    fn count_alloc(&self, size: usize) {
-        // Avoid re-entrancy here when metrics are first initialized.
-        if self.metering.load(Ordering::Acquire) {
-            ALLOCATED.inc_by(size as u64);
-            #[allow(clippy::cast_precision_loss)]
-            SIZE.observe(size as f64);
+/// Totals for threads of `kind`, excluding counts not flushed yet.
+#[must_use]
+pub fn kind_totals(kind: ThreadKind) -> KindTotals {
+    let totals = &TOTALS[kind as usize];
+    KindTotals {
+        allocated: totals.allocated.load(Ordering::Relaxed),
+        freed:     totals.freed.load(Ordering::Relaxed),
+        allocs:    totals.allocs.load(Ordering::Relaxed),
+        reallocs:  totals.reallocs.load(Ordering::Relaxed),
+        sizes:     totals
+            .sizes
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed)),
+    }
+}
+
+/// Global totals for one kind of thread.
+struct Totals {
+    allocated: AtomicU64,
+    freed:     AtomicU64,
+    allocs:    AtomicU64,
+    reallocs:  AtomicU64,
+    sizes:     [AtomicU64; BUCKETS],
+}
+
+impl Totals {
+    #[allow(clippy::declare_interior_mutable_const)] // Only used to initialize.
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self {
+        allocated: Self::ZERO,
+        freed:     Self::ZERO,
+        allocs:    Self::ZERO,
+        reallocs:  Self::ZERO,
+        sizes:     [Self::ZERO; BUCKETS],
+    };
+
+    fn add(&self, pending: &Pending) {
+        self.allocated
+            .fetch_add(pending.allocated, Ordering::Relaxed);
+        self.freed.fetch_add(pending.freed, Ordering::Relaxed);
+        self.allocs.fetch_add(pending.allocs, Ordering::Relaxed);
+        self.reallocs.fetch_add(pending.reallocs, Ordering::Relaxed);
+        for (total, &count) in self.sizes.iter().zip(&pending.sizes) {
+            if count > 0 {
+                total.fetch_add(count, Ordering::Relaxed);
+            }
         }
     }
+}
+
+static TOTALS: [Totals; ThreadKind::ALL.len()] = [Totals::NEW; ThreadKind::ALL.len()];
+
+// Live bytes can briefly go negative when memory is freed by a thread that
+// flushes before the allocating thread does.
+static LIVE_BYTES: AtomicI64 = AtomicI64::new(0);
+static PEAK_BYTES: AtomicI64 = AtomicI64::new(0);
+
+/// Allocations that would bring live bytes above this fail.
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+struct Local {
+    kind:        ThreadKind,
+    allocations: ThreadAllocations,
+    pending:     Pending,
+}
+
+thread_local! {
+    static LOCAL: RefCell<Local> = const {
+        RefCell::new(Local {
+            kind:        ThreadKind::Other,
+            allocations: ThreadAllocations { bytes: 0, count: 0 },
+            pending:     Pending::EMPTY,
+        })
+    };
+}
+
+/// Cumulative allocations made by a single thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadAllocations {
+    /// Bytes allocated, including growth by reallocation.
+    pub bytes: u64,
+    /// Number of allocations, excluding reallocations.
+    pub count: u64,
+}
+
+/// Allocations made by the current thread so far.
+#[must_use]
+pub fn thread_allocations() -> ThreadAllocations {
+    LOCAL
+        .try_with(|local| local.try_borrow().map(|local| local.allocations).ok())
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Attribute the current thread's allocations to `kind`.
+pub fn set_thread_kind(kind: ThreadKind) {
+    let _ = LOCAL.try_with(|local| {
+        if let Ok(mut local) = local.try_borrow_mut() {
+            if local.kind != kind {
+                flush(&mut local);
+                local.kind = kind;
+            }
+        }
+    });
+}
+
+/// Flush the current thread's pending counts, e.g. before it exits.
+pub fn flush_thread() {
+    let _ = LOCAL.try_with(|local| {
+        if let Ok(mut local) = local.try_borrow_mut() {
+            flush(&mut local);
+        }
+    });
+}
+
+fn flush(local: &mut Local) {
+    let pending = local.pending;
+    local.pending = Pending::EMPTY;
+    TOTALS[local.kind as usize].add(&pending);
+    #[allow(clippy::cast_possible_wrap)]
+    let delta = pending.allocated as i64 - pending.freed as i64;
+    let live = LIVE_BYTES.fetch_add(delta, Ordering::Relaxed) + delta;
+    PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+}
 
-    fn count_dealloc(&self, size: usize) {
-        if self.metering.load(Ordering::Acquire) {
-            FREED.inc_by(size as u64);
+/// Record an operation in the thread local accumulator, or directly in the
+/// totals if it is unavailable.
+fn record(update: impl Fn(&mut Pending)) {
+    let recorded = LOCAL.try_with(|local| {
+        let Ok(mut local) = local.try_borrow_mut() else {
+            return false;
+        };
+        let before = local.pending;
+        update(&mut local.pending);
+        let after = local.pending;
+        local.allocations.bytes += after.allocated - before.allocated;
+        local.allocations.count += after.allocs - before.allocs;
+        if after.should_flush() {
+            flush(&mut local);
         }
+        true
+    });
+    if recorded != Ok(true) {
+        let mut local = Local {
+            kind:        ThreadKind::Other,
+            allocations: ThreadAllocations::default(),
+            pending:     Pending::EMPTY,
+        };
+        update(&mut local.pending);
+        flush(&mut local);
+    }
+}
+
+/// Index of the allocation size bucket, with upper bounds `16 * 4^i`.
+const fn bucket(size: usize) -> usize {
+    if size <= 16 {
+        return 0;
+    }
+    let bits = (usize::BITS - (size - 1).leading_zeros()) as usize;
+    let index = (bits - 3) / 2;
+    if index < BUCKETS - 1 {
+        index
+    } else {
+        BUCKETS - 1
+    }
+}
+
+/// Snapshot of the allocator statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// Total bytes allocated, including growth by reallocation.
+    pub allocated_bytes: u64,
+    /// Bytes currently allocated.
+    pub live_bytes:      usize,
+    /// Highest value of `live_bytes` since start or [`reset_peak_bytes`].
+    pub peak_bytes:      usize,
+    /// Number of allocations, excluding reallocations.
+    pub alloc_count:     u64,
+    /// Number of reallocations.
+    pub realloc_count:   u64,
+}
+
+impl Display for AllocatorStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allocated {} in {} allocations, peak {}",
+            Bytes(self.allocated_bytes),
+            self.alloc_count,
+            Bytes(self.peak_bytes as u64)
+        )
+    }
+}
+
+/// Displays a byte count in binary units, e.g. `1.2 GiB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bytes(pub u64);
+
+impl Display for Bytes {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Current allocator statistics.
+///
+/// Counts from the calling thread are exact, other threads may have up to
+/// 64 KiB and 256 operations that are not included yet.
+#[must_use]
+pub fn allocator_stats() -> AllocatorStats {
+    flush_thread();
+    let sum = |field: fn(&Totals) -> &AtomicU64| {
+        TOTALS
+            .iter()
+            .map(|totals| field(totals).load(Ordering::Relaxed))
+            .sum()
+    };
+    AllocatorStats {
+        allocated_bytes: sum(|totals| &totals.allocated),
+        live_bytes:      load_bytes(&LIVE_BYTES),
+        peak_bytes:      load_bytes(&PEAK_BYTES),
+        alloc_count:     sum(|totals| &totals.allocs),
+        realloc_count:   sum(|totals| &totals.reallocs),
+    }
+}
+
+fn load_bytes(atomic: &AtomicI64) -> usize {
+    atomic.load(Ordering::Relaxed).try_into().unwrap_or_default()
+}
+
+/// Reset the peak to the current live bytes, returning the previous peak.
+pub fn reset_peak_bytes() -> usize {
+    flush_thread();
+    PEAK_BYTES
+        .swap(LIVE_BYTES.load(Ordering::Relaxed), Ordering::Relaxed)
+        .try_into()
+        .unwrap_or_default()
+}
+
+/// Make allocations fail when they would exceed `limit` live bytes.
+pub fn set_memory_limit(limit: usize) {
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Would allocating `size` more bytes exceed the memory limit?
+///
+/// This is the last resort, `memory_limit` shuts the program down before it.
+fn exceeds_limit(size: usize) -> bool {
+    load_bytes(&LIVE_BYTES).saturating_add(size) > LIMIT.load(Ordering::Relaxed)
+}
+
+pub struct MeteredAllocator<T: GlobalAlloc> {
+    inner: T,
+}
+
+impl<T: GlobalAlloc> MeteredAllocator<T> {
+    pub const fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Mark the calling thread as the main thread.
+    #[allow(clippy::unused_self)]
+    pub fn start_metering(&self) {
+        set_thread_kind(ThreadKind::Main);
+    }
+
+    fn count_alloc(size: usize) {
+        record(|pending| {
+            pending.allocs += 1;
+            pending.allocated += size as u64;
+            pending.sizes[bucket(size)] += 1;
+        });
+    }
+
+    fn count_realloc(old_size: usize, new_size: usize) {
+        record(|pending| {
+            pending.reallocs += 1;
+            if new_size >= old_size {
+                let growth = new_size - old_size;
+                pending.allocated += growth as u64;
+                pending.sizes[bucket(growth)] += 1;
+            } else {
+                pending.freed += (old_size - new_size) as u64;
+            }
+        });
+    }
+
+    fn count_dealloc(size: usize) {
+        record(|pending| pending.freed += size as u64);
     }
 }
 
@@ -65,27 +430,95 @@ impl<T: GlobalAlloc> MeteredAllocator<T> {
 #[allow(unsafe_code)]
 unsafe impl<T: GlobalAlloc> GlobalAlloc for MeteredAllocator<T> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.count_alloc(layout.size());
-        self.inner.alloc(layout)
+        if exceeds_limit(layout.size()) {
+            return null_mut();
+        }
+        Self::count_alloc(layout.size());
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            heap_profile::on_alloc(ptr, layout.size());
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.count_dealloc(layout.size());
+        Self::count_dealloc(layout.size());
+        heap_profile::on_dealloc(ptr);
         self.inner.dealloc(ptr, layout);
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        self.count_alloc(layout.size());
-        self.inner.alloc_zeroed(layout)
+        if exceeds_limit(layout.size()) {
+            return null_mut();
+        }
+        Self::count_alloc(layout.size());
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            heap_profile::on_alloc(ptr, layout.size());
+        }
+        ptr
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let old_size = layout.size();
-        if new_size >= old_size {
-            self.count_alloc(new_size - old_size);
-        } else {
-            self.count_dealloc(old_size - new_size);
+        if new_size > old_size && exceeds_limit(new_size - old_size) {
+            return null_mut();
+        }
+        Self::count_realloc(old_size, new_size);
+        heap_profile::on_dealloc(ptr);
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            // The old allocation is still valid, but we lost its sample.
+            return new_ptr;
         }
-        self.inner.realloc(ptr, layout, new_size)
+        heap_profile::on_alloc(new_ptr, new_size);
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocator_stats() {
+        const SIZE: usize = 64 << 20;
+
+        // Other tests allocate concurrently, so only check facts that hold
+        // regardless of what they do.
+        let before = allocator_stats();
+        let buffer = vec![1_u8; SIZE];
+        let during = allocator_stats();
+        assert!(during.alloc_count > before.alloc_count);
+        assert!(during.live_bytes >= SIZE);
+        assert!(during.peak_bytes >= SIZE);
+        drop(buffer);
+
+        // The peak includes the buffer until reset.
+        assert!(reset_peak_bytes() >= SIZE);
+
+        let mut buffer = Vec::with_capacity(1);
+        buffer.extend_from_slice(&[0_u8; 1024]);
+        assert_eq!(buffer.len(), 1024);
+        assert!(allocator_stats().realloc_count > before.realloc_count);
+    }
+
+    #[test]
+    fn test_bucket() {
+        for size in [0, 1, 16, 17, 64, 65, 1000, 1 << 20, 16 << 18, (16 << 18) + 1] {
+            let index = bucket(size);
+            #[allow(clippy::cast_precision_loss)]
+            let size = size as f64;
+            assert!(size <= KindTotals::bucket_bound(index));
+            assert!(index == 0 || size > KindTotals::bucket_bound(index - 1));
+        }
+        assert_eq!(bucket(usize::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_bytes() {
+        assert_eq!(Bytes(1000).to_string(), "1000 B");
+        assert_eq!(Bytes(1536).to_string(), "1.5 KiB");
+        assert_eq!(Bytes(1_288_490_189).to_string(), "1.2 GiB");
     }
 }
@@ -0,0 +1,157 @@
+//! Panic hook that logs panics through `tracing`.
+//!
+//! Before a subscriber is installed panics are printed by the `color-eyre`
+//! hook, which is also installed for `eyre` reports.
+
+use crate::{crash, default_from_clap, shutdown::shutdown, Version};
+use clap::Parser;
+use core::str::FromStr;
+use eyre::{bail, Error as EyreError, Result as EyreResult};
+use std::{
+    any::Any,
+    backtrace::{Backtrace, BacktraceStatus},
+    process,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    thread,
+};
+use tracing::{dispatcher, error};
+use tracing_error::SpanTrace;
+
+static BEHAVIOR: AtomicU8 = AtomicU8::new(PanicBehavior::Log as u8);
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// What to do after a panic is logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+enum PanicBehavior {
+    /// Continue without the panicked thread or task.
+    Log,
+    /// Shut down gracefully and exit with an error.
+    Shutdown,
+    /// Abort the process immediately.
+    Abort,
+}
+
+impl FromStr for PanicBehavior {
+    type Err = EyreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "log" => Self::Log,
+            "shutdown" => Self::Shutdown,
+            "abort" => Self::Abort,
+            _ => bail!("Invalid panic behavior: {}", s),
+        })
+    }
+}
+
+impl PanicBehavior {
+    fn get() -> Self {
+        match BEHAVIOR.load(Ordering::Relaxed) {
+            1 => Self::Shutdown,
+            2 => Self::Abort,
+            _ => Self::Log,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// What to do when a thread or task panics, one of 'log' to continue,
+    /// 'shutdown' to shut down gracefully and exit with an error, or 'abort'.
+    /// A panic in main always shuts down. When built with `--cfg
+    /// tokio_unstable`, 'shutdown' also stops the Tokio runtime on panics in
+    /// spawned tasks.
+    #[clap(long, env, default_value = "log")]
+    panic: PanicBehavior,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    pub fn init(self) {
+        BEHAVIOR.store(self.panic as u8, Ordering::Relaxed);
+    }
+
+    /// Make Tokio shut down the runtime on panics in spawned tasks when
+    /// shutting down on panics. Tokio only supports this with `--cfg
+    /// tokio_unstable`.
+    #[cfg(tokio_unstable)]
+    pub fn configure_runtime(self, runtime: &mut tokio::runtime::Builder) {
+        use tokio::runtime::UnhandledPanic;
+        runtime.unhandled_panic(match self.panic {
+            PanicBehavior::Shutdown => UnhandledPanic::ShutdownRuntime,
+            PanicBehavior::Log | PanicBehavior::Abort => UnhandledPanic::Ignore,
+        });
+    }
+}
+
+/// Install the panic hook and the `color-eyre` report hook.
+pub fn install(version: &Version) -> EyreResult<()> {
+    let (panic_hook, eyre_hook) = color_eyre::config::HookBuilder::default()
+        .issue_url(format!("{}/issues/new", version.pkg_repo))
+        .add_issue_metadata(
+            "version",
+            format!("{} {}", version.pkg_name, version.long_version),
+        )
+        .into_hooks();
+    eyre_hook.install()?;
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = thread::current();
+        let thread = thread.name().unwrap_or("<unnamed>");
+        if dispatcher::has_been_set() {
+            let backtrace = Backtrace::capture();
+            error!(
+                location = info.location().map(tracing::field::display),
+                thread,
+                backtrace = (backtrace.status() == BacktraceStatus::Captured)
+                    .then(|| tracing::field::display(&backtrace)),
+                span_trace = %SpanTrace::capture(),
+                "Panic: {}",
+                message(info.payload())
+            );
+        } else {
+            eprintln!("{}", panic_hook.panic_report(info));
+        }
+        crash::on_panic(thread, info.location(), message(info.payload()));
+        PANICKED.store(true, Ordering::Relaxed);
+        match PanicBehavior::get() {
+            PanicBehavior::Log => {}
+            PanicBehavior::Shutdown => shutdown(),
+            PanicBehavior::Abort => process::abort(),
+        }
+    }));
+    Ok(())
+}
+
+/// Fail if the program shut down because of a panic.
+pub fn check() -> EyreResult<()> {
+    if PanicBehavior::get() == PanicBehavior::Shutdown && PANICKED.load(Ordering::Relaxed) {
+        bail!("Shut down after a panic");
+    }
+    Ok(())
+}
+
+/// The message of a panic payload.
+pub fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        assert_eq!(message(&"static"), "static");
+        assert_eq!(message(&"owned".to_owned()), "owned");
+        assert_eq!(message(&42), "Box<dyn Any>");
+        assert_eq!("shutdown".parse::<PanicBehavior>().unwrap(), PanicBehavior::Shutdown);
+        assert!("exit".parse::<PanicBehavior>().is_err());
+    }
+}
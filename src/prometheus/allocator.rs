@@ -0,0 +1,181 @@
+#![cfg(feature = "metered-allocator")]
+use crate::metered_allocator::{
+    allocator_stats, kind_totals, KindTotals, ThreadKind, BUCKETS,
+};
+use eyre::Result as EyreResult;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Bucket, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType},
+    register,
+};
+use std::collections::HashMap;
+
+/// Register metrics for the metered allocator.
+pub fn register_allocator() -> EyreResult<()> {
+    register(Box::new(AllocatorCollector::new()?))?;
+    Ok(())
+}
+
+/// Exposes the allocator totals, reading them on every collection.
+struct AllocatorCollector {
+    descs: Vec<Desc>,
+}
+
+impl AllocatorCollector {
+    fn new() -> EyreResult<Self> {
+        let desc = |name: &str, help: &str, labels: &[&str]| {
+            Desc::new(
+                name.to_owned(),
+                help.to_owned(),
+                labels.iter().map(ToString::to_string).collect(),
+                HashMap::new(),
+            )
+        };
+        Ok(Self {
+            descs: vec![
+                desc("mem_alloc", "Cumulative memory allocated.", &["thread"])?,
+                desc("mem_free", "Cumulative memory freed.", &["thread"])?,
+                desc(
+                    "mem_allocs_total",
+                    "Cumulative number of allocations.",
+                    &["thread"],
+                )?,
+                desc(
+                    "mem_reallocs_total",
+                    "Cumulative number of reallocations.",
+                    &["thread"],
+                )?,
+                desc(
+                    "mem_alloc_size",
+                    "Distribution of allocation sizes.",
+                    &["thread"],
+                )?,
+                desc("mem_live_bytes", "Memory currently allocated.", &[])?,
+                desc(
+                    "mem_peak_bytes",
+                    "Peak memory allocated since start or last reset.",
+                    &[],
+                )?,
+            ],
+        })
+    }
+}
+
+impl Collector for AllocatorCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stats = allocator_stats();
+        let totals = ThreadKind::ALL.map(|kind| (kind, kind_totals(kind)));
+        let d = &self.descs;
+        vec![
+            family(&d[0], MetricType::COUNTER, counters(&totals, |t| t.allocated)),
+            family(&d[1], MetricType::COUNTER, counters(&totals, |t| t.freed)),
+            family(&d[2], MetricType::COUNTER, counters(&totals, |t| t.allocs)),
+            family(&d[3], MetricType::COUNTER, counters(&totals, |t| t.reallocs)),
+            family(&d[4], MetricType::HISTOGRAM, histograms(&totals)),
+            family(&d[5], MetricType::GAUGE, gauge(stats.live_bytes)),
+            family(&d[6], MetricType::GAUGE, gauge(stats.peak_bytes)),
+        ]
+    }
+}
+
+fn family(desc: &Desc, kind: MetricType, metrics: Vec<Metric>) -> MetricFamily {
+    let mut family = MetricFamily::default();
+    family.set_name(desc.fq_name.clone());
+    family.set_help(desc.help.clone());
+    family.set_field_type(kind);
+    family.set_metric(metrics.into());
+    family
+}
+
+fn thread_metric(kind: ThreadKind) -> Metric {
+    let mut label = LabelPair::default();
+    label.set_name("thread".to_owned());
+    label.set_value(kind.to_string());
+    let mut metric = Metric::default();
+    metric.set_label(vec![label].into());
+    metric
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn counters(totals: &[(ThreadKind, KindTotals)], field: fn(&KindTotals) -> u64) -> Vec<Metric> {
+    totals
+        .iter()
+        .map(|(kind, totals)| {
+            let mut counter = Counter::default();
+            counter.set_value(field(totals) as f64);
+            let mut metric = thread_metric(*kind);
+            metric.set_counter(counter);
+            metric
+        })
+        .collect()
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn histograms(totals: &[(ThreadKind, KindTotals)]) -> Vec<Metric> {
+    totals
+        .iter()
+        .map(|(kind, totals)| {
+            let mut cumulative = 0;
+            let mut buckets = totals
+                .sizes
+                .iter()
+                .enumerate()
+                .map(|(index, count)| {
+                    cumulative += count;
+                    let mut bucket = Bucket::default();
+                    bucket.set_upper_bound(KindTotals::bucket_bound(index));
+                    bucket.set_cumulative_count(cumulative);
+                    bucket
+                })
+                .collect::<Vec<_>>();
+            // The encoder adds the `+Inf` bucket from the sample count.
+            buckets.truncate(BUCKETS - 1);
+            let mut histogram = Histogram::default();
+            histogram.set_sample_count(cumulative);
+            histogram.set_sample_sum(totals.allocated as f64);
+            histogram.set_bucket(buckets.into());
+            let mut metric = thread_metric(*kind);
+            metric.set_histogram(histogram);
+            metric
+        })
+        .collect()
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn gauge(value: usize) -> Vec<Metric> {
+    let mut gauge = Gauge::default();
+    gauge.set_value(value as f64);
+    let mut metric = Metric::default();
+    metric.set_gauge(gauge);
+    vec![metric]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metered_allocator::{flush_thread, set_thread_kind};
+    use prometheus::Encoder as _;
+
+    #[test]
+    fn test_register_allocator() {
+        register_allocator().unwrap();
+        set_thread_kind(ThreadKind::Main);
+        drop(vec![0_u8; 1 << 20]);
+        flush_thread();
+
+        let mut text = vec![];
+        prometheus::TextEncoder
+            .encode(&prometheus::gather(), &mut text)
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("mem_live_bytes"));
+        assert!(text.contains("mem_peak_bytes"));
+        assert!(text.contains("mem_allocs_total{thread=\"main\"}"));
+        assert!(text.contains("mem_reallocs_total{thread=\"rayon\"}"));
+        assert!(text.contains("mem_alloc_size_bucket{thread=\"main\",le=\"+Inf\"}"));
+    }
+}
@@ -0,0 +1,109 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use eyre::{bail, ensure, Result as EyreResult, WrapErr as _};
+use hyper::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    Body, HeaderMap, Response,
+};
+use std::{fs, path::Path};
+
+/// Credentials required to access the metrics server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Auth {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// `Authorization: Basic <base64 of user:password>`
+    Basic(String),
+}
+
+impl Auth {
+    /// Read credentials from a token file or a `user:password` file.
+    ///
+    /// Leading and trailing whitespace in the files is ignored.
+    pub fn from_files(token: Option<&Path>, basic: Option<&Path>) -> EyreResult<Option<Self>> {
+        let read = |path: &Path| {
+            fs::read_to_string(path)
+                .map(|s| s.trim().to_owned())
+                .wrap_err_with(|| format!("Error reading {}", path.display()))
+        };
+        Ok(match (token, basic) {
+            (None, None) => None,
+            (Some(token), None) => {
+                let token = read(token)?;
+                ensure!(!token.is_empty(), "Empty bearer token");
+                Some(Self::Bearer(token))
+            }
+            (None, Some(basic)) => {
+                let credentials = read(basic)?;
+                ensure!(
+                    credentials.contains(':'),
+                    "Basic auth credentials must be of the form user:password"
+                );
+                Some(Self::Basic(STANDARD.encode(credentials)))
+            }
+            (Some(_), Some(_)) => bail!("Only one of bearer token or basic auth can be used"),
+        })
+    }
+
+    /// Check the `Authorization` header of a request.
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(header) = headers.get(AUTHORIZATION) else {
+            return false;
+        };
+        let (scheme, expected) = match self {
+            Self::Bearer(token) => ("Bearer ", token),
+            Self::Basic(credentials) => ("Basic ", credentials),
+        };
+        header
+            .as_bytes()
+            .strip_prefix(scheme.as_bytes())
+            .is_some_and(|provided| constant_time_eq(provided, expected.as_bytes()))
+    }
+
+    /// Response for requests without valid credentials.
+    pub fn unauthorized(&self) -> Response<Body> {
+        let challenge = match self {
+            Self::Bearer(_) => "Bearer",
+            Self::Basic(_) => "Basic realm=\"metrics\"",
+        };
+        Response::builder()
+            .status(401)
+            .header(WWW_AUTHENTICATE, challenge)
+            .body(Body::from("401"))
+            .unwrap()
+    }
+}
+
+/// Compare secrets without leaking their common prefix length through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(authorization: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static(authorization));
+        headers
+    }
+
+    #[test]
+    fn test_bearer() {
+        let auth = Auth::Bearer("secret".to_owned());
+        assert!(auth.is_authorized(&headers("Bearer secret")));
+        assert!(!auth.is_authorized(&headers("Bearer secreT")));
+        assert!(!auth.is_authorized(&headers("Bearer secret2")));
+        assert!(!auth.is_authorized(&headers("Basic secret")));
+        assert!(!auth.is_authorized(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_basic() {
+        let auth = Auth::Basic(STANDARD.encode("user:pass"));
+        assert!(auth.is_authorized(&headers("Basic dXNlcjpwYXNz")));
+        assert!(!auth.is_authorized(&headers("Basic dXNlcjpwYXNx")));
+        assert!(!auth.is_authorized(&headers("Bearer dXNlcjpwYXNz")));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{heartbeat::uptime, Version};
+use eyre::Result as EyreResult;
+use prometheus::{
+    core::{Collector, Desc},
+    opts,
+    proto::MetricFamily,
+    register, register_int_gauge_vec, Gauge,
+};
+
+/// Whether each of the listed features of this crate is compiled in.
+macro_rules! features {
+    ($($feature:literal),* $(,)?) => {
+        &[$(($feature, cfg!(feature = $feature))),*]
+    };
+}
+
+/// Features of this crate that are compiled in. Checked against `Cargo.toml`
+/// in the tests.
+const FEATURES: &[(&str, bool)] = features![
+    "signals",
+    "mock-shutdown",
+    "metered-allocator",
+    "tokio-console",
+    "mimalloc",
+    "jemalloc",
+    "rand",
+    "rayon",
+    "prometheus",
+    "tls",
+    "profiling",
+    "opentelemetry",
+    "otlp",
+    "datadog",
+];
+
+/// Gauge that is updated to the current uptime on every collection.
+struct Uptime(Gauge);
+
+impl Collector for Uptime {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.0.set(uptime().as_secs_f64());
+        self.0.collect()
+    }
+}
+
+/// Register metrics describing the build and the running process.
+pub fn register_info(version: &Version) -> EyreResult<()> {
+    let build_info = register_int_gauge_vec!(
+        "build_info",
+        "Build information, the value is always 1.",
+        &["version", "commit", "target", "rustc", "profile"]
+    )?;
+    build_info
+        .with_label_values(&[
+            version.pkg_version,
+            version.commit_hash,
+            version.target,
+            version.rustc,
+            version.profile,
+        ])
+        .set(1);
+
+    let features = register_int_gauge_vec!(
+        "cli_batteries_feature_enabled",
+        "Whether a cli-batteries feature is enabled (1) or not (0).",
+        &["feature"]
+    )?;
+    for (feature, enabled) in FEATURES {
+        features
+            .with_label_values(&[feature])
+            .set((*enabled).into());
+    }
+
+    register(Box::new(Uptime(Gauge::with_opts(opts!(
+        "uptime_seconds",
+        "Time since the program started in seconds."
+    ))?)))?;
+
+    // On Linux this is already provided by the process collector.
+    #[cfg(not(target_os = "linux"))]
+    {
+        use prometheus::register_gauge;
+        use std::time::SystemTime;
+
+        let start = SystemTime::now() - uptime();
+        register_gauge!(
+            "process_start_time_seconds",
+            "Start time of the process since unix epoch in seconds."
+        )?
+        .set(start.duration_since(SystemTime::UNIX_EPOCH)?.as_secs_f64());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus::Encoder as _;
+
+    #[test]
+    fn test_register_info() {
+        let version = Version::test();
+        register_info(&version).unwrap();
+
+        let mut buffer = vec![];
+        prometheus::TextEncoder
+            .encode(&prometheus::gather(), &mut buffer)
+            .unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains(
+            "build_info{commit=\"7cdd3615368b7e2ed1e053f33628fe7f65e6a538\",profile=\"debug\",\
+             rustc=\"rustc 1.69.0 (84c898d65 2023-04-16)\",target=\"aarch64-apple-darwin\",\
+             version=\"v0.0.0\"} 1"
+        ));
+        assert!(text.contains("cli_batteries_feature_enabled{feature=\"prometheus\"} 1"));
+        assert!(text.contains("process_start_time_seconds"));
+        assert!(text.contains("uptime_seconds"));
+    }
+
+    #[test]
+    fn test_features() {
+        let manifest = include_str!("../../Cargo.toml");
+        let mut features = manifest
+            .split("\n[features]\n")
+            .nth(1)
+            .and_then(|section| section.split("\n[").next())
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.split_once(" = ").map(|(name, _)| name.trim()))
+            .filter(|name| !name.is_empty() && *name != "default")
+            .collect::<Vec<_>>();
+        features.sort_unstable();
+        let mut listed = FEATURES.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        listed.sort_unstable();
+        assert_eq!(listed, features);
+    }
+}
@@ -0,0 +1,290 @@
+#![cfg(feature = "prometheus")]
+mod allocator;
+mod auth;
+mod info;
+mod native_allocator;
+mod profiling;
+mod push;
+mod rayon;
+mod tls;
+
+use self::auth::Auth;
+use crate::{default_from_clap, shutdown::await_shutdown, Version};
+use clap::Parser;
+use eyre::{bail, ensure, Result as EyreResult, WrapErr as _};
+use futures::{future::BoxFuture, FutureExt as _};
+use hyper::{
+    body::HttpBody,
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    opts, register_counter, register_gauge, register_histogram, Counter, Encoder as _, Gauge,
+    Histogram,
+};
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{error, info, instrument, trace};
+use url::{Host, Url};
+
+#[cfg(feature = "opentelemetry")]
+use crate::trace_from_headers;
+
+// TODO: Spans, traceId and SpanKind trace_span!("request", "otel.kind" =
+// %SpanKind::Server, "http.url" = ..),
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Prometheus scrape endpoint, `https://` requires the `tls` feature and
+    /// a certificate and key.
+    // See <https://github.com/prometheus/prometheus/wiki/Default-port-allocations>
+    #[clap(long, env, default_value = "http://127.0.0.1:9998/metrics")]
+    pub prometheus: Url,
+
+    /// Certificate chain PEM file for the https:// metrics server. Reloaded on
+    /// change.
+    #[cfg(feature = "tls")]
+    #[clap(long, env)]
+    pub prometheus_tls_cert: Option<PathBuf>,
+
+    /// Private key PEM file for the https:// metrics server. Reloaded on
+    /// change.
+    #[cfg(feature = "tls")]
+    #[clap(long, env)]
+    pub prometheus_tls_key: Option<PathBuf>,
+
+    /// CA certificates PEM file. If set, clients of the https:// metrics
+    /// server must present a certificate signed by one of these (mTLS).
+    #[cfg(feature = "tls")]
+    #[clap(long, env)]
+    pub prometheus_tls_client_ca: Option<PathBuf>,
+
+    /// File containing a bearer token required to access the metrics server.
+    #[clap(long, env)]
+    pub prometheus_token_file: Option<PathBuf>,
+
+    /// File containing `user:password` credentials required to access the
+    /// metrics server using basic authentication.
+    #[clap(long, env)]
+    pub prometheus_basic_auth_file: Option<PathBuf>,
+
+    /// Push metrics to a Prometheus Pushgateway, for jobs that finish before
+    /// they are scraped. Example: `http://127.0.0.1:9091`
+    #[clap(long, env)]
+    pub prometheus_push: Option<Url>,
+
+    /// Interval in seconds between pushes to the Pushgateway. A final push is
+    /// always made on shutdown.
+    #[clap(
+        long,
+        env,
+        default_value = "15",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub prometheus_push_interval: u64,
+}
+
+default_from_clap!(Options);
+
+static REQ_COUNTER: Lazy<Counter> = Lazy::new(|| {
+    register_counter!(opts!(
+        "prometheus_requests_total",
+        "Number of Prometheus scrape requests made."
+    ))
+    .unwrap()
+});
+static REQ_BODY_GAUGE: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(opts!(
+        "prometheus_response_size_bytes",
+        "The Prometheus response sizes in bytes."
+    ))
+    .unwrap()
+});
+static REQ_HISTOGRAM: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "prometheus_request_duration_seconds",
+        "The Prometheus request latencies in seconds."
+    )
+    .unwrap()
+});
+
+#[allow(clippy::unnecessary_wraps)]
+#[allow(clippy::needless_pass_by_value)]
+#[allow(clippy::unused_async)]
+async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    // let encoder = prometheus::ProtobufEncoder;
+    let encoder = prometheus::TextEncoder;
+    let metric_families = prometheus::gather();
+    let mut buffer = vec![];
+    let result = encoder.encode(&metric_families, &mut buffer);
+    if let Err(e) = result {
+        error!("Internal server error: {}", e);
+        let response = Response::builder()
+            .status(500)
+            .body(Body::from(e.to_string()))
+            .unwrap(); // TODO
+        return Ok(response);
+    }
+
+    let response = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap(); // TODO
+
+    Ok(response)
+}
+
+#[cfg(feature = "metered-allocator")]
+async fn heap_profile() -> Response<Body> {
+    if !crate::heap_profile::is_enabled() {
+        return Response::builder()
+            .status(404)
+            .body(Body::from("Heap profiling is disabled, see --heap-profile-rate"))
+            .unwrap();
+    }
+    // Resolving symbols can take seconds.
+    match tokio::task::spawn_blocking(crate::heap_profile::folded).await {
+        Ok(profile) => Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(profile))
+            .unwrap(),
+        Err(err) => {
+            error!(?err, "Error creating heap profile: {}", err);
+            Response::builder()
+                .status(500)
+                .body(Body::from("Error creating heap profile"))
+                .unwrap()
+        }
+    }
+}
+
+#[allow(clippy::unused_async)] // We are implementing an interface
+#[instrument(level="debug", name="prometheus_request", skip(req, auth), fields(http.uri = %req.uri(), http.method = %req.method()))]
+async fn route(
+    req: Request<Body>,
+    auth: Option<Arc<Auth>>,
+) -> Result<Response<Body>, hyper::Error> {
+    #[cfg(feature = "opentelemetry")]
+    trace_from_headers(req.headers());
+
+    trace!("Receiving request at path {}", req.uri());
+    REQ_COUNTER.inc();
+    let timer = REQ_HISTOGRAM.start_timer();
+
+    let response = if let Some(auth) = auth.filter(|auth| !auth.is_authorized(req.headers())) {
+        auth.unauthorized()
+    } else {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => serve_req(req).await?,
+            #[cfg(feature = "profiling")]
+            (&Method::GET, "/debug/pprof/profile") => profiling::profile(&req).await,
+            #[cfg(feature = "metered-allocator")]
+            (&Method::GET, "/debug/pprof/heap") => heap_profile().await,
+            _ => Response::builder()
+                .status(404)
+                .body(Body::from("404"))
+                .unwrap(),
+        }
+    };
+
+    #[allow(clippy::cast_precision_loss)]
+    REQ_BODY_GAUGE.set(response.body().size_hint().lower() as f64);
+    timer.observe_duration();
+    Ok(response)
+}
+
+pub async fn main(options: Options, version: Version) -> EyreResult<()> {
+    ensure!(
+        options.prometheus.path() == "/metrics",
+        "Only /metrics is supported in {}",
+        options.prometheus
+    );
+    let ip: IpAddr = match options.prometheus.host() {
+        Some(Host::Ipv4(ip)) => ip.into(),
+        Some(Host::Ipv6(ip)) => ip.into(),
+        Some(_) => bail!("Cannot bind {}", options.prometheus),
+        None => Ipv4Addr::LOCALHOST.into(),
+    };
+    let port = options.prometheus.port().unwrap_or(9998);
+    let addr = SocketAddr::new(ip, port);
+    let auth = Auth::from_files(
+        options.prometheus_token_file.as_deref(),
+        options.prometheus_basic_auth_file.as_deref(),
+    )?
+    .map(Arc::new);
+
+    info::register_info(&version)?;
+    #[cfg(feature = "metered-allocator")]
+    allocator::register_allocator()?;
+    #[cfg(any(feature = "mimalloc", feature = "jemalloc"))]
+    native_allocator::register_native_allocator()?;
+    #[cfg(feature = "rayon")]
+    rayon::register_rayon()?;
+
+    let listener = TcpListener::bind(addr).wrap_err("Could not bind Prometheus server port")?;
+    listener.set_nonblocking(true)?;
+    let server: BoxFuture<EyreResult<()>> = match options.prometheus.scheme() {
+        "http" => serve_http(listener, auth)?.boxed(),
+        #[cfg(feature = "tls")]
+        "https" => {
+            let files = tls::files_from_options(
+                options.prometheus_tls_cert.as_deref(),
+                options.prometheus_tls_key.as_deref(),
+                options.prometheus_tls_client_ca.as_deref(),
+            )?
+            .ok_or_else(|| eyre::eyre!("https:// requires a TLS certificate and key"))?;
+            let config = Arc::new(tls::ReloadingConfig::new(files)?);
+            tls::serve(listener, config, auth).boxed()
+        }
+        _ => bail!("Unsupported scheme in {}", options.prometheus),
+    };
+    info!(url = %options.prometheus, "Metrics server listening");
+
+    if let Some(gateway) = options.prometheus_push {
+        let period = Duration::from_secs(options.prometheus_push_interval);
+        let (server, push) = tokio::join!(server, push::main(gateway, period, version));
+        server?;
+        push?;
+    } else {
+        server.await?;
+    }
+    Ok(())
+}
+
+fn serve_http(
+    listener: TcpListener,
+    auth: Option<Arc<Auth>>,
+) -> EyreResult<impl Future<Output = EyreResult<()>>> {
+    let server = Server::from_tcp(listener)?
+        .serve(make_service_fn(move |_| {
+            let auth = auth.clone();
+            async move { Ok::<_, hyper::Error>(service_fn(move |req| route(req, auth.clone()))) }
+        }))
+        .with_graceful_shutdown(await_shutdown());
+    Ok(async { Ok(server.await?) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_interval() {
+        let parse = |interval| {
+            Options::try_parse_from(["arg0", "--prometheus-push-interval", interval])
+                .map(|options| options.prometheus_push_interval)
+        };
+        assert_eq!(parse("60").unwrap(), 60);
+        assert!(parse("0").is_err());
+    }
+}
@@ -0,0 +1,58 @@
+#![cfg(any(feature = "mimalloc", feature = "jemalloc"))]
+use crate::allocator::{native_stats, NativeStats};
+use eyre::Result as EyreResult;
+use prometheus::{
+    core::{Collector, Desc},
+    opts,
+    proto::MetricFamily,
+    register, IntGaugeVec,
+};
+
+/// Gauges that are updated from the allocator statistics on every collection.
+struct NativeAllocator(IntGaugeVec);
+
+impl Collector for NativeAllocator {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.desc()
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn collect(&self) -> Vec<MetricFamily> {
+        for (stat, value) in native_stats().fields() {
+            self.0
+                .with_label_values(&[NativeStats::ALLOCATOR, stat])
+                .set(value as i64);
+        }
+        self.0.collect()
+    }
+}
+
+/// Register metrics reported by the mimalloc or jemalloc allocator.
+pub fn register_native_allocator() -> EyreResult<()> {
+    register(Box::new(NativeAllocator(IntGaugeVec::new(
+        opts!("allocator_bytes", "Statistics reported by the allocator in bytes."),
+        &["allocator", "stat"],
+    )?)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus::Encoder as _;
+
+    #[test]
+    fn test_register_native_allocator() {
+        register_native_allocator().unwrap();
+        let mut text = vec![];
+        prometheus::TextEncoder
+            .encode(&prometheus::gather(), &mut text)
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let [(stat, _), ..] = native_stats().fields();
+        assert!(text.contains(&format!(
+            "allocator_bytes{{allocator=\"{}\",stat=\"{stat}\"}}",
+            NativeStats::ALLOCATOR
+        )));
+    }
+}
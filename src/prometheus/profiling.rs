@@ -0,0 +1,208 @@
+#![cfg(feature = "profiling")]
+use crate::shutdown::{await_shutdown, is_shutting_down};
+use core::sync::atomic::{AtomicBool, Ordering};
+use eyre::{bail, Result as EyreResult};
+use hyper::{header::CONTENT_TYPE, Body, Request, Response};
+use pprof::{protos::Message as _, ProfilerGuardBuilder, Report};
+use std::{fmt::Write as _, time::Duration};
+use tokio::time::sleep;
+use tracing::{error, info};
+use url::form_urlencoded;
+
+/// Sampling frequency in Hz. Slightly off from 100 to avoid lockstep sampling.
+const FREQUENCY: i32 = 99;
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+
+/// Set while a profile is being collected. Only one profiler can run at a
+/// time since it uses process wide signal handlers.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Clears [`RUNNING`] on drop, even if the request is cancelled.
+struct RunningGuard;
+
+impl RunningGuard {
+    fn acquire() -> Option<Self> {
+        (!RUNNING.swap(true, Ordering::AcqRel)).then(|| Self)
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Protobuf for `go tool pprof`.
+    Pprof,
+    /// Folded stacks for `inferno` or `flamegraph.pl`.
+    Folded,
+    /// Flamegraph SVG.
+    Flamegraph,
+}
+
+fn parse_query(query: Option<&str>) -> EyreResult<(Duration, Format)> {
+    let mut seconds = DEFAULT_SECONDS;
+    let mut format = Format::Pprof;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "seconds" => seconds = value.parse()?,
+            "format" => {
+                format = match value.as_ref() {
+                    "pprof" => Format::Pprof,
+                    "folded" => Format::Folded,
+                    "flamegraph" | "svg" => Format::Flamegraph,
+                    _ => bail!("Invalid format: {}", value),
+                }
+            }
+            _ => bail!("Unknown parameter: {}", key),
+        }
+    }
+    if seconds == 0 || seconds > MAX_SECONDS {
+        bail!("seconds must be between 1 and {}", MAX_SECONDS);
+    }
+    Ok((Duration::from_secs(seconds), format))
+}
+
+fn response(status: u16, body: impl Into<Body>) -> Response<Body> {
+    Response::builder().status(status).body(body.into()).unwrap()
+}
+
+/// Handle `GET /debug/pprof/profile?seconds=N&format=pprof|folded|flamegraph`.
+pub async fn profile(req: &Request<Body>) -> Response<Body> {
+    let (duration, format) = match parse_query(req.uri().query()) {
+        Ok(params) => params,
+        Err(err) => return response(400, err.to_string()),
+    };
+    if is_shutting_down() {
+        return response(503, "Shutting down");
+    }
+    let Some(_running) = RunningGuard::acquire() else {
+        return response(409, "A profile is already being collected");
+    };
+
+    info!(?duration, ?format, "Collecting CPU profile");
+    let guard = match ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(err) => {
+            error!(?err, "Error starting profiler: {}", err);
+            return response(500, err.to_string());
+        }
+    };
+    tokio::select! {
+        () = sleep(duration) => {},
+        () = await_shutdown() => return response(503, "Shutting down"),
+    };
+    // Resolving symbols and rendering can take seconds.
+    let result = tokio::task::spawn_blocking(move || {
+        let result = guard
+            .report()
+            .build()
+            .map_err(eyre::Report::from)
+            .and_then(|report| encode(&report, format));
+        drop(guard);
+        result
+    })
+    .await
+    .map_err(eyre::Report::from)
+    .and_then(|result| result);
+
+    match result {
+        Ok((content_type, body)) => Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap(),
+        Err(err) => {
+            error!(?err, "Error creating profile: {}", err);
+            response(500, err.to_string())
+        }
+    }
+}
+
+fn encode(report: &Report, format: Format) -> EyreResult<(&'static str, Vec<u8>)> {
+    Ok(match format {
+        Format::Pprof => ("application/octet-stream", report.pprof()?.encode_to_vec()),
+        Format::Folded => ("text/plain", folded(report).into_bytes()),
+        Format::Flamegraph => {
+            let mut svg = vec![];
+            report.flamegraph(&mut svg)?;
+            ("image/svg+xml", svg)
+        }
+    })
+}
+
+/// Render the report as folded stacks, one `thread;outer;..;inner count` line
+/// per unique stack.
+fn folded(report: &Report) -> String {
+    let mut result = String::new();
+    for (frames, count) in &report.data {
+        result.push_str(&frames.thread_name_or_id());
+        for symbol in frames.frames.iter().rev().flat_map(|frame| frame.iter().rev()) {
+            write!(result, ";{symbol}").unwrap();
+        }
+        writeln!(result, " {count}").unwrap();
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query(None).unwrap(),
+            (Duration::from_secs(30), Format::Pprof)
+        );
+        assert_eq!(
+            parse_query(Some("seconds=5&format=folded")).unwrap(),
+            (Duration::from_secs(5), Format::Folded)
+        );
+        assert!(parse_query(Some("seconds=0")).is_err());
+        assert!(parse_query(Some("seconds=1000")).is_err());
+        assert!(parse_query(Some("format=png")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_profile() {
+        let request = |query| {
+            Request::get(format!("/debug/pprof/profile?seconds=1&format={query}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let busy = std::thread::spawn(|| {
+            let start = std::time::Instant::now();
+            let mut x = 0_u64;
+            while start.elapsed() < Duration::from_secs(2) {
+                x = x.wrapping_mul(31).wrapping_add(1);
+            }
+            x
+        });
+
+        // Concurrent profiles are rejected.
+        let (first, second) = (request("folded"), request("folded"));
+        let (first, (second, third)) = tokio::join!(profile(&first), async {
+            sleep(Duration::from_millis(100)).await;
+            (profile(&second).await, profile(&second).await)
+        });
+        assert_eq!(first.status(), 200);
+        assert_eq!(second.status(), 409);
+        assert_eq!(third.status(), 409);
+        let body = to_bytes(first.into_body()).await.unwrap();
+        assert!(!body.is_empty());
+
+        let pprof = profile(&request("pprof")).await;
+        assert_eq!(pprof.status(), 200);
+        busy.join().unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{shutdown::await_shutdown, Version};
+use eyre::{bail, ensure, eyre, Result as EyreResult, WrapErr as _};
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request};
+use prometheus::Encoder as _;
+use std::time::Duration;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tracing::{error, info, instrument, warn};
+use url::Url;
+
+/// Number of attempts made for a single push before giving up.
+const ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled on every subsequent retry.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Periodically push metrics to a Pushgateway, and once more on shutdown.
+pub async fn main(gateway: Url, period: Duration, version: Version) -> EyreResult<()> {
+    let url = grouping_url(&gateway, &version)?;
+    info!(%url, "Pushing metrics to Pushgateway");
+
+    let mut interval = interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.reset(); // Skip immediate first tick
+
+    loop {
+        tokio::select! {
+            () = await_shutdown() => break,
+            _ = interval.tick() => {},
+        };
+        if let Err(err) = push_with_retry(&url).await {
+            error!(?err, "Failed to push metrics: {}", err);
+        }
+    }
+
+    // Final push so the last values of a short-lived job are not lost.
+    push_with_retry(&url)
+        .await
+        .wrap_err("Failed to push final metrics")
+}
+
+/// Construct the Pushgateway URL with the `job` and `instance` grouping
+/// labels.
+///
+/// See <https://github.com/prometheus/pushgateway#url>
+fn grouping_url(gateway: &Url, version: &Version) -> EyreResult<Url> {
+    ensure!(
+        gateway.scheme() == "http",
+        "Only http:// is supported in {}",
+        gateway
+    );
+    let commit = version.commit_hash.get(..8).unwrap_or(version.commit_hash);
+    let instance = format!("{}-{}", version.pkg_version, commit);
+    let mut url = gateway.clone();
+    url.path_segments_mut()
+        .map_err(|()| eyre!("Invalid Pushgateway url {}", gateway))?
+        .pop_if_empty()
+        .extend(["metrics", "job", version.pkg_name, "instance", &instance]);
+    Ok(url)
+}
+
+async fn push_with_retry(url: &Url) -> EyreResult<()> {
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..ATTEMPTS {
+        match push(url).await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!(attempt, ?delay, "Pushing metrics failed, retrying: {}", err);
+                sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+    push(url).await
+}
+
+#[instrument(level = "debug", skip_all, fields(http.url = %url))]
+async fn push(url: &Url) -> EyreResult<()> {
+    let encoder = prometheus::TextEncoder;
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(url.as_str())
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))?;
+    let response = Client::new().request(request).await?;
+    if !response.status().is_success() {
+        bail!("Pushgateway responded with {}", response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{
+        body::to_bytes,
+        service::{make_service_fn, service_fn},
+        Response, Server,
+    };
+    use prometheus::{register_int_counter, IntCounter};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_grouping_url() {
+        let gateway = "http://127.0.0.1:9091/".parse().unwrap();
+        let url = grouping_url(&gateway, &Version::test()).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://127.0.0.1:9091/metrics/job/cli-test/instance/v0.0.0-7cdd3615"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_with_retry() {
+        let counter: IntCounter =
+            register_int_counter!("push_test_total", "Counter for the push test.").unwrap();
+        counter.inc_by(42);
+
+        // Stand-in Pushgateway that fails the first request.
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            let requests = requests.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let sender = sender.clone();
+                    let requests = requests.clone();
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = to_bytes(body).await?;
+                        sender
+                            .send((parts.method, parts.uri.path().to_owned(), body))
+                            .unwrap();
+                        let status = if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                            500
+                        } else {
+                            200
+                        };
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server =
+            Server::bind(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).serve(make_service);
+        let gateway = format!("http://{}", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        let url = grouping_url(&gateway, &Version::test()).unwrap();
+        push_with_retry(&url).await.unwrap();
+
+        for _ in 0..2 {
+            let (method, path, body) = receiver.recv().await.unwrap();
+            assert_eq!(method, Method::PUT);
+            assert_eq!(path, "/metrics/job/cli-test/instance/v0.0.0-7cdd3615");
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains("push_test_total 42"));
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+}
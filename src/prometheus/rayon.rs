@@ -0,0 +1,138 @@
+#![cfg(feature = "rayon")]
+use crate::rayon::rayon_stats;
+use eyre::Result as EyreResult;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType},
+    register,
+};
+use std::collections::HashMap;
+
+/// Register metrics for the Rayon thread pool.
+pub fn register_rayon() -> EyreResult<()> {
+    register(Box::new(RayonCollector::new()?))?;
+    Ok(())
+}
+
+/// Exposes the Rayon pool statistics, reading them on every collection.
+struct RayonCollector {
+    descs: Vec<Desc>,
+}
+
+impl RayonCollector {
+    fn new() -> EyreResult<Self> {
+        let desc = |name: &str, help: &str, labels: &[&str]| {
+            Desc::new(
+                name.to_owned(),
+                help.to_owned(),
+                labels.iter().map(ToString::to_string).collect(),
+                HashMap::new(),
+            )
+        };
+        Ok(Self {
+            descs: vec![
+                desc(
+                    "rayon_tasks_spawned_total",
+                    "Number of tasks started on the Rayon pool.",
+                    &[],
+                )?,
+                desc(
+                    "rayon_tasks_completed_total",
+                    "Number of Rayon tasks that completed without panicking.",
+                    &[],
+                )?,
+                desc(
+                    "rayon_tasks_stolen_total",
+                    "Number of Rayon tasks that ran on another thread than they were started on.",
+                    &[],
+                )?,
+                desc(
+                    "rayon_task_busy_seconds_total",
+                    "Time each Rayon thread spent running tasks started with the helpers.",
+                    &["thread"],
+                )?,
+                desc("rayon_threads", "Number of threads in the Rayon pool.", &[])?,
+            ],
+        })
+    }
+}
+
+impl Collector for RayonCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stats = rayon_stats();
+        let busy = stats
+            .busy
+            .iter()
+            .enumerate()
+            .map(|(index, busy)| {
+                let mut label = LabelPair::default();
+                label.set_name("thread".to_owned());
+                label.set_value(index.to_string());
+                let mut metric = counter(busy.as_secs_f64());
+                metric.set_label(vec![label].into());
+                metric
+            })
+            .collect();
+        let d = &self.descs;
+        vec![
+            family(&d[0], MetricType::COUNTER, vec![counter(stats.spawned as f64)]),
+            family(&d[1], MetricType::COUNTER, vec![counter(stats.completed as f64)]),
+            family(&d[2], MetricType::COUNTER, vec![counter(stats.stolen as f64)]),
+            family(&d[3], MetricType::COUNTER, busy),
+            family(&d[4], MetricType::GAUGE, vec![gauge(
+                rayon::current_num_threads() as f64,
+            )]),
+        ]
+    }
+}
+
+fn family(desc: &Desc, kind: MetricType, metrics: Vec<Metric>) -> MetricFamily {
+    let mut family = MetricFamily::default();
+    family.set_name(desc.fq_name.clone());
+    family.set_help(desc.help.clone());
+    family.set_field_type(kind);
+    family.set_metric(metrics.into());
+    family
+}
+
+fn counter(value: f64) -> Metric {
+    let mut counter = Counter::default();
+    counter.set_value(value);
+    let mut metric = Metric::default();
+    metric.set_counter(counter);
+    metric
+}
+
+fn gauge(value: f64) -> Metric {
+    let mut gauge = Gauge::default();
+    gauge.set_value(value);
+    let mut metric = Metric::default();
+    metric.set_gauge(gauge);
+    metric
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rayon::rayon_join;
+    use prometheus::Encoder as _;
+
+    #[test]
+    fn test_register_rayon() {
+        register_rayon().unwrap();
+        rayon_join(|| (), || ());
+        let mut text = vec![];
+        prometheus::TextEncoder
+            .encode(&prometheus::gather(), &mut text)
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("rayon_tasks_spawned_total"));
+        assert!(text.contains("rayon_tasks_completed_total"));
+        assert!(text.contains("rayon_threads"));
+    }
+}
@@ -0,0 +1,423 @@
+#![cfg(feature = "tls")]
+use super::{auth::Auth, route};
+use crate::shutdown::await_shutdown;
+use eyre::{ensure, eyre, Result as EyreResult, WrapErr as _};
+use hyper::{server::conn::Http, service::service_fn};
+use rustls::{
+    server::AllowAnyAuthenticatedClient, sign, Certificate, PrivateKey, RootCertStore,
+    ServerConfig, SignatureScheme,
+};
+use rustls_pemfile::Item;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    time::{interval, sleep, timeout, MissedTickBehavior},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+use webpki::{EndEntityCert, SignatureAlgorithm};
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before accepting connections again after an error, such as running
+/// out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Time a client has to complete the TLS handshake before it is disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEM files for the TLS server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert:      PathBuf,
+    pub key:       PathBuf,
+    /// Require client certificates signed by these CAs (mTLS).
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Server configuration that is rebuilt when the files change.
+pub struct ReloadingConfig {
+    files:   TlsFiles,
+    current: RwLock<(Vec<Vec<u8>>, Arc<ServerConfig>)>,
+}
+
+impl ReloadingConfig {
+    pub fn new(files: TlsFiles) -> EyreResult<Self> {
+        let contents = files.read()?;
+        let config = server_config(&contents)?;
+        Ok(Self {
+            files,
+            current: RwLock::new((contents, config)),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().1.clone())
+    }
+
+    /// Reload the configuration if any of the files changed. Returns `true`
+    /// if the configuration was replaced.
+    ///
+    /// On error the previous configuration remains in use.
+    pub fn reload(&self) -> EyreResult<bool> {
+        let contents = self.files.read()?;
+        if contents == self.current.read().unwrap().0 {
+            return Ok(false);
+        }
+        let config = server_config(&contents)?;
+        *self.current.write().unwrap() = (contents, config);
+        Ok(true)
+    }
+}
+
+impl TlsFiles {
+    fn read(&self) -> EyreResult<Vec<Vec<u8>>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                fs::read(path).wrap_err_with(|| format!("Error reading {}", path.display()))
+            })
+            .collect()
+    }
+}
+
+/// Build a server configuration from the contents of the cert, key and
+/// optional client CA files.
+fn server_config(contents: &[Vec<u8>]) -> EyreResult<Arc<ServerConfig>> {
+    let certs = read_pem(&contents[0])?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    ensure!(!certs.is_empty(), "No certificates found in TLS cert file");
+    let key = read_pem(&contents[1])?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| eyre!("No private key found in TLS key file"))?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = if let Some(client_ca) = contents.get(2) {
+        let mut roots = RootCertStore::empty();
+        for item in read_pem(client_ca)? {
+            if let Item::X509Certificate(cert) = item {
+                roots.add(&Certificate(cert))?;
+            }
+        }
+        ensure!(!roots.is_empty(), "No certificates found in TLS client CA file");
+        builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+    } else {
+        builder.with_no_client_auth()
+    };
+    check_key_pair(&certs[0], &key)?;
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Check that the private key belongs to the certificate. The files are
+/// read separately, so a reload during rotation can see a new certificate
+/// with the old key.
+fn check_key_pair(cert: &Certificate, key: &PrivateKey) -> EyreResult<()> {
+    const MESSAGE: &[u8] = b"cli-batteries TLS key pair check";
+    const SCHEMES: [(SignatureScheme, &SignatureAlgorithm); 4] = [
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (SignatureScheme::RSA_PSS_SHA256, &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+    ];
+    let signer = sign::any_supported_type(key)
+        .map_err(|_| eyre!("Unsupported private key in TLS key file"))?
+        .choose_scheme(&SCHEMES.map(|(scheme, _)| scheme))
+        .ok_or_else(|| eyre!("Unsupported private key in TLS key file"))?;
+    let algorithm = SCHEMES
+        .iter()
+        .find_map(|(scheme, algorithm)| (*scheme == signer.scheme()).then_some(*algorithm))
+        .ok_or_else(|| eyre!("Unsupported private key in TLS key file"))?;
+    let signature = signer.sign(MESSAGE)?;
+    EndEntityCert::try_from(cert.0.as_slice())
+        .and_then(|cert| cert.verify_signature(algorithm, MESSAGE, &signature))
+        .map_err(|_| eyre!("TLS key does not match the certificate"))
+}
+
+fn read_pem(mut pem: &[u8]) -> EyreResult<Vec<Item>> {
+    rustls_pemfile::read_all(&mut pem).wrap_err("Error parsing PEM file")
+}
+
+/// Serve HTTPS until shutdown, reloading the certificates when they change.
+pub async fn serve(
+    listener: std::net::TcpListener,
+    config: Arc<ReloadingConfig>,
+    auth: Option<Arc<Auth>>,
+) -> EyreResult<()> {
+    let listener = TcpListener::from_std(listener)?;
+    tokio::spawn(watch(config.clone()));
+    loop {
+        let (stream, peer) = tokio::select! {
+            () = await_shutdown() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(?err, "Error accepting connection: {}", err);
+                    sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+        };
+        let acceptor = config.acceptor();
+        let auth = auth.clone();
+        tokio::spawn(async move {
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!(%peer, "TLS handshake failed: {}", err);
+                    return;
+                }
+                Err(_) => {
+                    debug!(%peer, "TLS handshake timed out");
+                    return;
+                }
+            };
+            let service = service_fn(move |req| route(req, auth.clone()));
+            let connection = Http::new().serve_connection(stream, service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                () = await_shutdown() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(err) = result {
+                debug!(%peer, "Error serving connection: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn watch(config: Arc<ReloadingConfig>) {
+    let mut interval = interval(RELOAD_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.reset(); // Skip immediate first tick
+    loop {
+        tokio::select! {
+            () = await_shutdown() => break,
+            _ = interval.tick() => {},
+        };
+        match config.reload() {
+            Ok(true) => info!(cert = %config.files.cert.display(), "Reloaded TLS certificates"),
+            Ok(false) => {}
+            Err(err) => error!(?err, "Error reloading TLS certificates: {}", err),
+        }
+    }
+}
+
+/// Check that either both or neither of cert and key are given.
+pub fn files_from_options(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    client_ca: Option<&Path>,
+) -> EyreResult<Option<TlsFiles>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some(TlsFiles {
+            cert:      cert.to_owned(),
+            key:       key.to_owned(),
+            client_ca: client_ca.map(Path::to_owned),
+        })),
+        (None, None) => {
+            ensure!(client_ca.is_none(), "TLS client CA requires a cert and key");
+            Ok(None)
+        }
+        _ => Err(eyre!("TLS requires both a cert and a key")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{
+        body::to_bytes,
+        client::conn::handshake,
+        header::{AUTHORIZATION, HOST},
+        Body, Request, StatusCode,
+    };
+    use rcgen::{BasicConstraints, Certificate as RcgenCertificate, CertificateParams, IsCa};
+    use rustls::{ClientConfig, ServerName};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tempfile::TempDir;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    struct Pki {
+        dir:    TempDir,
+        ca:     RcgenCertificate,
+        ca_pem: String,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = RcgenCertificate::from_params(params).unwrap();
+            let ca_pem = ca.serialize_pem().unwrap();
+            fs::write(dir.path().join("ca.pem"), &ca_pem).unwrap();
+            Self { dir, ca, ca_pem }
+        }
+
+        /// Issue a certificate signed by the CA, returns (cert, key) PEM.
+        fn issue(&self, name: &str) -> (String, String) {
+            let cert = RcgenCertificate::from_params(CertificateParams::new(vec![name.into()]))
+                .unwrap();
+            (
+                cert.serialize_pem_with_signer(&self.ca).unwrap(),
+                cert.serialize_private_key_pem(),
+            )
+        }
+
+        fn write_server_cert(&self) {
+            let (cert, key) = self.issue("localhost");
+            fs::write(self.dir.path().join("cert.pem"), cert).unwrap();
+            fs::write(self.dir.path().join("key.pem"), key).unwrap();
+        }
+
+        fn files(&self, mtls: bool) -> TlsFiles {
+            TlsFiles {
+                cert:      self.dir.path().join("cert.pem"),
+                key:       self.dir.path().join("key.pem"),
+                client_ca: mtls.then(|| self.dir.path().join("ca.pem")),
+            }
+        }
+
+        fn client(&self, client_cert: bool) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            for item in read_pem(self.ca_pem.as_bytes()).unwrap() {
+                if let Item::X509Certificate(cert) = item {
+                    roots.add(&Certificate(cert)).unwrap();
+                }
+            }
+            let builder = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots);
+            let config = if client_cert {
+                let (cert, key) = self.issue("client");
+                let Item::X509Certificate(cert) = read_pem(cert.as_bytes()).unwrap().remove(0)
+                else {
+                    panic!("Expected certificate")
+                };
+                let Item::PKCS8Key(key) = read_pem(key.as_bytes()).unwrap().remove(0) else {
+                    panic!("Expected key")
+                };
+                builder
+                    .with_client_auth_cert(vec![Certificate(cert)], PrivateKey(key))
+                    .unwrap()
+            } else {
+                builder.with_no_client_auth()
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    fn start(files: TlsFiles, auth: Option<Auth>) -> (SocketAddr, Arc<ReloadingConfig>) {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(ReloadingConfig::new(files).unwrap());
+        tokio::spawn(serve(listener, config.clone(), auth.map(Arc::new)));
+        (addr, config)
+    }
+
+    async fn get(
+        addr: SocketAddr,
+        connector: &TlsConnector,
+        authorization: Option<&str>,
+    ) -> EyreResult<(StatusCode, String)> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        let (mut sender, connection) = handshake(stream).await?;
+        tokio::spawn(connection);
+        let mut request = Request::get("/metrics").header(HOST, "localhost");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let response = sender.send_request(request.body(Body::empty())?).await?;
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await?;
+        Ok((status, String::from_utf8(body.to_vec())?))
+    }
+
+    #[tokio::test]
+    async fn test_https_with_auth() {
+        let pki = Pki::new();
+        pki.write_server_cert();
+        let auth = Auth::Bearer("secret".to_owned());
+        let (addr, _config) = start(pki.files(false), Some(auth));
+        let client = pki.client(false);
+
+        let (status, _) = get(addr, &client, None).await.unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = get(addr, &client, Some("Bearer wrong")).await.unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = get(addr, &client, Some("Bearer secret")).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("prometheus_requests_total"));
+    }
+
+    #[tokio::test]
+    async fn test_mtls() {
+        let pki = Pki::new();
+        pki.write_server_cert();
+        let (addr, _config) = start(pki.files(true), None);
+
+        assert!(get(addr, &pki.client(false), None).await.is_err());
+        let (status, _) = get(addr, &pki.client(true), None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let pki = Pki::new();
+        pki.write_server_cert();
+        let (addr, config) = start(pki.files(false), None);
+        assert!(!config.reload().unwrap());
+
+        // Clients trusting the new CA only work after reloading.
+        let new_pki = Pki::new();
+        let new_client = new_pki.client(false);
+        assert!(get(addr, &new_client, None).await.is_err());
+        new_pki.write_server_cert();
+        fs::copy(new_pki.dir.path().join("cert.pem"), pki.dir.path().join("cert.pem")).unwrap();
+        fs::copy(new_pki.dir.path().join("key.pem"), pki.dir.path().join("key.pem")).unwrap();
+        assert!(config.reload().unwrap());
+        let (status, _) = get(addr, &new_client, None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        // A certificate with a key that does not belong to it keeps the
+        // previous configuration, as when the files are being replaced.
+        let (_, other_key) = new_pki.issue("localhost");
+        fs::write(pki.dir.path().join("key.pem"), other_key).unwrap();
+        assert!(config.reload().is_err());
+        let (status, _) = get(addr, &new_client, None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        // A broken file keeps the previous configuration.
+        fs::write(pki.dir.path().join("key.pem"), "garbage").unwrap();
+        assert!(config.reload().is_err());
+        let (status, _) = get(addr, &new_client, None).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+}
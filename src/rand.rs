@@ -1,9 +1,37 @@
 #![cfg(feature = "rand")]
+//! Deterministic random number generation.
+//!
+//! All generators derive from a single seed that is logged at startup and
+//! can be set with `--random-seed` to reproduce a run. Use [`rng`] for a
+//! shared generator and [`fork_rng`] for independent streams whose output
+//! does not depend on the order in which other code draws random numbers.
+
 use clap::Parser;
+use once_cell::sync::{Lazy, OnceCell};
 use rand::{rngs::OsRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha8Rng;
-use std::num::ParseIntError;
-use tracing::info;
+use std::{
+    cell::RefCell,
+    num::ParseIntError,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+use tracing::{info, warn};
+
+static SEED: OnceCell<u64> = OnceCell::new();
+
+static GLOBAL: Lazy<Mutex<ChaCha8Rng>> =
+    Lazy::new(|| Mutex::new(ChaCha8Rng::seed_from_u64(random_seed())));
+
+/// Number of generators created for threads outside the Rayon pool, to give
+/// each its own stream.
+static THREADS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD: RefCell<Option<ChaCha8Rng>> = const { RefCell::new(None) };
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Parser)]
 #[group(skip)]
@@ -17,16 +45,184 @@ pub struct Options {
 impl Options {
     pub fn init(&self) {
         // Initialize randomness source
-        let rng_seed = self
-            .random_seed
-            .unwrap_or_else(|| OsRng::default().next_u64());
-        info!("Using random seed {rng_seed:016x}");
-        let _rng = ChaCha8Rng::seed_from_u64(rng_seed);
-        // TODO: Use `rng` to create deterministic runs
+        if let Some(seed) = self.random_seed {
+            if SEED.set(seed).is_err() {
+                warn!(
+                    "Random numbers were used before initialization, ignoring --random-seed \
+                     {seed:016x}"
+                );
+            }
+        }
+        info!("Using random seed {:016x}", random_seed());
+    }
+}
+
+/// The seed all random number generators are derived from.
+///
+/// Generated from OS entropy on first use if `--random-seed` was not given.
+#[must_use]
+pub fn random_seed() -> u64 {
+    *SEED.get_or_init(|| OsRng.next_u64())
+}
+
+/// The seed, if it was set or random numbers were used.
+pub fn used_seed() -> Option<u64> {
+    SEED.get().copied()
+}
+
+/// Handle to the global random number generator.
+///
+/// The handle can be shared between threads. Results are only reproducible
+/// if the order in which threads draw from it is, so prefer [`fork_rng`]
+/// for concurrent work.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SharedRng;
+
+/// The global random number generator seeded from [`random_seed`].
+#[must_use]
+pub const fn rng() -> SharedRng {
+    SharedRng
+}
+
+impl RngCore for SharedRng {
+    fn next_u32(&mut self) -> u32 {
+        with_global(RngCore::next_u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        with_global(RngCore::next_u64)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        with_global(|rng| rng.fill_bytes(dest));
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        with_global(|rng| rng.try_fill_bytes(dest))
     }
 }
 
+fn with_global<R>(f: impl FnOnce(&mut ChaCha8Rng) -> R) -> R {
+    f(&mut GLOBAL.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// Create an independent random number generator for `label`.
+///
+/// The generator only depends on the seed and the label, so the same label
+/// gives the same stream in every run with the same seed. Use a label
+/// identifying the unit of work, e.g. `format!("worker-{i}")` for the `i`-th
+/// Tokio task, as tasks are not tied to threads.
+#[must_use]
+pub fn fork_rng(label: &str) -> ChaCha8Rng {
+    derive(random_seed(), label)
+}
+
+/// Run `f` with a random number generator for the current thread.
+///
+/// On Rayon pool threads the generator is forked from the thread index, so
+/// the `i`-th pool thread gets the same stream in every run with the same
+/// seed. Other threads are numbered in the order in which they first use
+/// their generator, which is generally not deterministic, so use
+/// [`fork_rng`] there where that matters.
+pub fn with_thread_rng<R>(f: impl FnOnce(&mut ChaCha8Rng) -> R) -> R {
+    THREAD.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        f(rng.get_or_insert_with(|| fork_rng(&thread_label())))
+    })
+}
+
+fn thread_label() -> String {
+    #[cfg(feature = "rayon")]
+    if let Some(index) = rayon::current_thread_index() {
+        return format!("rayon-{index}");
+    }
+    let index = THREADS.fetch_add(1, Ordering::Relaxed);
+    format!("thread-{index}")
+}
+
+/// Derive a generator from the seed and the label using a separate `ChaCha`
+/// stream for each label.
+fn derive(seed: u64, label: &str) -> ChaCha8Rng {
+    // FNV-1a, as it must be stable across runs and Rust versions.
+    let stream = label.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(stream);
+    rng
+}
+
 fn parse_hex_u64(src: &str) -> Result<u64, ParseIntError> {
     let src = src.strip_prefix("0x").unwrap_or(src);
     u64::from_str_radix(src, 16)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_derive() {
+        let mut a = derive(0x1234, "worker-0");
+        let mut b = derive(0x1234, "worker-0");
+        let mut c = derive(0x1234, "worker-1");
+        let mut d = derive(0x5678, "worker-0");
+        let first = a.next_u64();
+        assert_eq!(first, b.next_u64());
+        assert_ne!(first, c.next_u64());
+        assert_ne!(first, d.next_u64());
+        assert_eq!(derive(0x1234, "worker-0").next_u64(), 0x854a_e924_d220_25fe);
+    }
+
+    #[test]
+    fn test_fork_rng() {
+        let mut a = fork_rng("test");
+        let _ = rng().next_u64();
+        assert_eq!(a.next_u64(), fork_rng("test").next_u64());
+    }
+
+    #[test]
+    fn test_with_thread_rng() {
+        let stream = with_thread_rng(|rng| rng.get_stream());
+        assert_eq!(with_thread_rng(|rng| rng.get_stream()), stream);
+
+        // Threads with the same name get different streams
+        let other = || {
+            std::thread::Builder::new()
+                .name("worker".to_owned())
+                .spawn(|| with_thread_rng(|rng| rng.get_stream()))
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        let (a, b) = (other(), other());
+        assert_ne!(a, b);
+        assert_ne!(a, stream);
+        assert_ne!(b, stream);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_with_thread_rng_rayon() {
+        // Fresh pools have fresh threads, like a second run with the same seed
+        let run = || {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(4)
+                .build()
+                .unwrap();
+            let mut streams = pool.broadcast(|context| {
+                (
+                    context.index(),
+                    with_thread_rng(|rng| (rng.get_stream(), rng.next_u64())),
+                )
+            });
+            streams.sort_unstable();
+            streams
+        };
+        let first = run();
+        assert_eq!(first, run());
+        for (index, (stream, _)) in &first {
+            assert_eq!(*stream, fork_rng(&format!("rayon-{index}")).get_stream());
+        }
+    }
+}
@@ -1,33 +1,556 @@
 #![cfg(feature = "rayon")]
-use crate::default_from_clap;
+use crate::{cgroup, default_from_clap, shutdown::is_shutting_down, size::parse_bytes};
 use clap::Parser;
-use eyre::{Result, WrapErr};
+use eyre::{bail, ensure, Result, WrapErr};
+use once_cell::sync::OnceCell;
+#[cfg(feature = "prometheus")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "prometheus")]
+use prometheus::{exponential_buckets, register_histogram, Histogram};
 use rayon::ThreadPoolBuilder;
-use tracing::info;
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+use tracing::{info, info_span, span::EnteredSpan, warn, Span};
+
+#[cfg(feature = "metered-allocator")]
+use crate::metered_allocator::{flush_thread, set_thread_kind, ThreadKind};
+
+static SPAWNED: AtomicU64 = AtomicU64::new(0);
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static STOLEN: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "prometheus")]
+static QUEUE_WAIT: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "rayon_queue_wait_seconds",
+        "Time tasks from `spawn_compute` waited for a Rayon thread.",
+        exponential_buckets(0.000_01, 4.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+/// Busy time of each pool thread in nanoseconds, set by `init`.
+static BUSY: OnceCell<Box<[AtomicU64]>> = OnceCell::new();
+
+thread_local! {
+    /// Span entered for the lifetime of a pool thread.
+    static THREAD_SPAN: RefCell<Option<EnteredSpan>> = const { RefCell::new(None) };
+
+    /// Start of the outermost task running on this pool thread, `None` when
+    /// there is none or it is waiting for other tasks.
+    static BUSY_SINCE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
 #[group(skip)]
 pub struct Options {
-    /// Number of compute threads to use. Defaults to number of cores.
+    /// Number of compute threads to use. Defaults to the number of cores
+    /// available to the process, taking cgroup limits into account.
     #[clap(long, env)]
     threads: Option<usize>,
+
+    /// Stack size of compute threads, e.g. `8M`. Defaults to Rayon's default.
+    #[clap(long, env, value_parser = parse_bytes)]
+    rayon_stack_size: Option<usize>,
+
+    /// Pin each compute thread to a core. Linux only.
+    #[clap(long, env)]
+    rayon_pin_cores: bool,
+
+    /// Number of cores to leave to Tokio when pinning compute threads. These
+    /// are the first cores the process may run on. Tokio workers are not
+    /// pinned, so they still also run on the other cores.
+    #[clap(long, env, default_value = "0")]
+    rayon_reserved_cores: usize,
+
+    /// Nice value of compute threads, e.g. `10` so compute does not starve
+    /// async I/O. Lower values require privileges. Linux only.
+    #[clap(long, env, allow_hyphen_values = true)]
+    rayon_nice: Option<i32>,
 }
 
 default_from_clap!(Options);
 
 impl Options {
     pub fn init(&self) -> Result<()> {
-        let num_cpus = num_cpus::get();
-        let threads = self.threads.unwrap_or(num_cpus);
-        ThreadPoolBuilder::new()
+        let num_cpus = cgroup::effective_cpus();
+        let cores = if self.rayon_pin_cores {
+            let allowed = os::allowed_cores().wrap_err("Failed to read CPU affinity.")?;
+            ensure!(
+                self.rayon_reserved_cores < allowed.len(),
+                "Cannot reserve {} of {} cores",
+                self.rayon_reserved_cores,
+                allowed.len()
+            );
+            allowed[self.rayon_reserved_cores..].to_vec()
+        } else {
+            Vec::new()
+        };
+        let threads = self.threads.unwrap_or(if cores.is_empty() {
+            num_cpus
+        } else {
+            cores.len()
+        });
+        let nice = self.rayon_nice;
+        let _ = BUSY.set((0..threads).map(|_| AtomicU64::new(0)).collect());
+        #[cfg(feature = "prometheus")]
+        Lazy::force(&QUEUE_WAIT);
+        let mut builder = ThreadPoolBuilder::new()
             .num_threads(threads)
+            .thread_name(|index| format!("rayon-{index}"))
+            .exit_handler(exit_handler)
+            .panic_handler(panic_handler);
+        if let Some(stack_size) = self.rayon_stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder
+            .start_handler(move |index| start_handler(index, &cores, nice))
             .build_global()
             .wrap_err("Failed to build thread pool.")?;
         info!(
-            "Using {} compute threads on {} cores",
+            stack_size = self.rayon_stack_size,
+            pin_cores = self.rayon_pin_cores,
+            reserved_cores = self.rayon_reserved_cores,
+            nice = self.rayon_nice,
+            "Using {} compute threads on {} of {} cores",
             rayon::current_num_threads(),
-            num_cpus
+            num_cpus,
+            cgroup::host_cpus()
         );
         Ok(())
     }
 }
+
+fn start_handler(index: usize, cores: &[usize], nice: Option<i32>) {
+    #[cfg(feature = "metered-allocator")]
+    set_thread_kind(ThreadKind::Rayon);
+    let span = info_span!("rayon", thread = index).entered();
+    if !cores.is_empty() {
+        let core = cores[index % cores.len()];
+        if let Err(err) = os::pin_to_core(core) {
+            warn!(?err, core, "Error pinning compute thread: {}", err);
+        }
+    }
+    if let Some(nice) = nice {
+        if let Err(err) = os::set_nice(nice) {
+            warn!(?err, nice, "Error setting compute thread nice value: {}", err);
+        }
+    }
+    let _ = THREAD_SPAN.try_with(|cell| cell.replace(Some(span)));
+}
+
+fn exit_handler(_index: usize) {
+    let _ = THREAD_SPAN.try_with(RefCell::take);
+    #[cfg(feature = "metered-allocator")]
+    flush_thread();
+}
+
+/// Ignore panics in tasks started with `spawn`, which the panic hook already
+/// logged. Without a handler Rayon aborts.
+#[allow(clippy::needless_pass_by_value)] // Signature required by Rayon.
+fn panic_handler(_payload: Box<dyn Any + Send>) {}
+
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+mod os {
+    use libc::{
+        cpu_set_t, sched_getaffinity, sched_setaffinity, setpriority, CPU_ISSET, CPU_SET,
+        CPU_SETSIZE, CPU_ZERO, PRIO_PROCESS,
+    };
+    use std::{
+        io::{Error, Result},
+        mem::{size_of, zeroed},
+        ptr::{addr_of, addr_of_mut},
+    };
+
+    /// Cores the calling thread may run on.
+    pub fn allowed_cores() -> Result<Vec<usize>> {
+        // SAFETY: An all zero `cpu_set_t` is valid and the size is correct.
+        unsafe {
+            let mut set: cpu_set_t = zeroed();
+            if sched_getaffinity(0, size_of::<cpu_set_t>(), addr_of_mut!(set)) != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok((0..CPU_SETSIZE as usize)
+                .filter(|&core| CPU_ISSET(core, &set))
+                .collect())
+        }
+    }
+
+    /// Restrict the calling thread to `core`.
+    pub fn pin_to_core(core: usize) -> Result<()> {
+        // SAFETY: An all zero `cpu_set_t` is valid and the size is correct.
+        unsafe {
+            let mut set: cpu_set_t = zeroed();
+            CPU_ZERO(&mut set);
+            CPU_SET(core, &mut set);
+            if sched_setaffinity(0, size_of::<cpu_set_t>(), addr_of!(set)) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the nice value of the calling thread. On Linux this is per thread.
+    pub fn set_nice(nice: i32) -> Result<()> {
+        // SAFETY: Plain system call.
+        if unsafe { setpriority(PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod os {
+    use std::io::{Error, ErrorKind, Result};
+
+    pub fn allowed_cores() -> Result<Vec<usize>> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    pub fn pin_to_core(_core: usize) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    pub fn set_nice(_nice: i32) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+}
+
+/// Wrap `f` to run in the current span, wherever it is called.
+pub fn with_current_span<F, R>(f: F) -> impl FnOnce() -> R + Send
+where
+    F: FnOnce() -> R + Send,
+{
+    let span = Span::current();
+    move || span.in_scope(f)
+}
+
+/// [`rayon::join`] running both closures in the current span.
+pub fn rayon_join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    let (task_a, task_b) = (Task::new(), Task::new());
+    let _waiting = Waiting::enter();
+    rayon::join(move || task_a.run(a), move || task_b.run(b))
+}
+
+/// [`rayon::spawn`] running `f` in the current span.
+pub fn rayon_spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let task = Task::new();
+    rayon::spawn(move || task.run(f));
+}
+
+/// Run `f` on the Rayon pool in the current span and await the result.
+///
+/// Work that has not started when the future is dropped or the program is
+/// shutting down is skipped. Panics in `f` are resumed in the awaiting task.
+/// With the `prometheus` feature the time spent queued is recorded in the
+/// `rayon_queue_wait_seconds` histogram.
+///
+/// # Errors
+///
+/// Returns an error if the work was skipped because the program is shutting
+/// down.
+pub fn spawn_compute<F, R>(f: F) -> impl Future<Output = Result<R>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let task = Task::new();
+    let queued = Instant::now();
+    rayon::spawn(move || {
+        #[cfg(feature = "prometheus")]
+        QUEUE_WAIT.observe(queued.elapsed().as_secs_f64());
+        #[cfg(not(feature = "prometheus"))]
+        let _ = queued;
+        if sender.is_closed() || is_shutting_down() {
+            return;
+        }
+        let _ = sender.send(catch_unwind(AssertUnwindSafe(|| task.run(f))));
+    });
+    async move {
+        match receiver.await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(panic)) => resume_unwind(panic),
+            Err(_) => bail!("Compute task cancelled because the program is shutting down"),
+        }
+    }
+}
+
+/// [`rayon::scope`] running `op` and the tasks it spawns in the current span.
+pub fn rayon_scope<'scope, OP, R>(op: OP) -> R
+where
+    OP: FnOnce(&Scope<'_, 'scope>) -> R + Send,
+    R: Send,
+{
+    let task = Task::new();
+    let _waiting = Waiting::enter();
+    rayon::scope(|scope| task.run(|| op(&Scope(scope))))
+}
+
+/// A [`rayon::Scope`] whose tasks run in the span they were spawned in.
+#[derive(Clone, Copy)]
+pub struct Scope<'a, 'scope>(&'a rayon::Scope<'scope>);
+
+impl<'scope> Scope<'_, 'scope> {
+    /// Spawn a task in the scope that runs in the current span.
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: for<'b> FnOnce(&Scope<'b, 'scope>) + Send + 'scope,
+    {
+        let task = Task::new();
+        self.0.spawn(move |scope| task.run(|| f(&Scope(scope))));
+    }
+}
+
+/// Statistics of the work started through the helpers in this module.
+///
+/// Rayon has no hooks around the jobs it runs, so other work, such as a
+/// `par_iter` that is not inside one of these tasks, is not included.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RayonStats {
+    /// Tasks started, counting both closures of a join.
+    pub spawned:   u64,
+    /// Tasks that returned without panicking.
+    pub completed: u64,
+    /// Tasks that ran on another pool thread than the one that started them.
+    pub stolen:    u64,
+    /// Time each pool thread spent running tasks, excluding the time a task
+    /// waited in [`rayon_join`] or [`rayon_scope`] for other tasks.
+    pub busy:      Vec<Duration>,
+}
+
+impl RayonStats {
+    /// Total time pool threads spent running tasks.
+    #[must_use]
+    pub fn busy_time(&self) -> Duration {
+        self.busy.iter().sum()
+    }
+
+    /// Fraction of the pool capacity spent running tasks in the `elapsed`
+    /// time since the `earlier` statistics were read.
+    #[must_use]
+    pub fn utilization(&self, earlier: &Self, elapsed: Duration) -> Option<f64> {
+        #[allow(clippy::cast_precision_loss)]
+        let capacity = elapsed.as_secs_f64() * self.busy.len() as f64;
+        (capacity > 0.0).then(|| {
+            self.busy_time().saturating_sub(earlier.busy_time()).as_secs_f64() / capacity
+        })
+    }
+}
+
+/// Read the Rayon pool statistics.
+#[must_use]
+pub fn rayon_stats() -> RayonStats {
+    RayonStats {
+        spawned:   SPAWNED.load(Ordering::Relaxed),
+        completed: COMPLETED.load(Ordering::Relaxed),
+        stolen:    STOLEN.load(Ordering::Relaxed),
+        busy:      BUSY.get().map_or_else(Vec::new, |busy| {
+            busy.iter()
+                .map(|nanos| Duration::from_nanos(nanos.load(Ordering::Relaxed)))
+                .collect()
+        }),
+    }
+}
+
+/// A unit of work that runs in the span it was created in and is counted in
+/// the pool statistics.
+struct Task {
+    span:    Span,
+    spawner: Option<usize>,
+}
+
+impl Task {
+    fn new() -> Self {
+        SPAWNED.fetch_add(1, Ordering::Relaxed);
+        Self {
+            span:    Span::current(),
+            spawner: rayon::current_thread_index(),
+        }
+    }
+
+    fn run<R>(self, f: impl FnOnce() -> R) -> R {
+        let worker = rayon::current_thread_index();
+        if self.spawner.is_some() && worker != self.spawner {
+            STOLEN.fetch_add(1, Ordering::Relaxed);
+        }
+        let _busy = Busy::enter(worker);
+        let result = self.span.in_scope(f);
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+}
+
+/// Adds the time spent in the outermost task on a pool thread to its busy time.
+struct Busy {
+    worker: Option<usize>,
+}
+
+impl Busy {
+    fn enter(worker: Option<usize>) -> Self {
+        let outermost = worker.is_some() && BUSY_SINCE.with(Cell::get).is_none();
+        if outermost {
+            BUSY_SINCE.with(|since| since.set(Some(Instant::now())));
+        }
+        Self {
+            worker: worker.filter(|_| outermost),
+        }
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if self.worker.is_some() {
+            stop_busy(self.worker);
+        }
+    }
+}
+
+/// Pauses the busy time of the current task while it waits for other tasks.
+/// Tasks that run on this thread in the meantime count as outermost tasks.
+struct Waiting {
+    paused: bool,
+}
+
+impl Waiting {
+    fn enter() -> Self {
+        Self {
+            paused: stop_busy(rayon::current_thread_index()),
+        }
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.paused {
+            BUSY_SINCE.with(|since| since.set(Some(Instant::now())));
+        }
+    }
+}
+
+/// Add the time since the outermost task started or resumed to the busy time
+/// of `worker`. Returns whether a task was running.
+fn stop_busy(worker: Option<usize>) -> bool {
+    let Some(start) = BUSY_SINCE.with(Cell::take) else {
+        return false;
+    };
+    if let Some(busy) = worker.and_then(|worker| BUSY.get()?.get(worker)) {
+        #[allow(clippy::cast_possible_truncation)] // 584 years
+        busy.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use tracing::{dispatcher::with_default, Dispatch, Metadata};
+
+    #[test]
+    fn test_span_propagation() {
+        // Pool threads don't have the default subscriber of the test thread, so
+        // it is set wherever the current span is read.
+        let dispatch = Dispatch::new(tracing_subscriber::registry());
+        let spans = Mutex::new(Vec::new());
+        let record = || {
+            let span = with_default(&dispatch, Span::current);
+            spans.lock().unwrap().push(span.metadata().map(Metadata::name));
+        };
+        with_default(&dispatch, || {
+            let _span = info_span!("caller").entered();
+            rayon_join(record, record);
+            rayon_scope(|scope| {
+                with_default(&dispatch, || {
+                    scope.spawn(|scope| {
+                        with_default(&dispatch, || scope.spawn(|_| record()));
+                        record();
+                    });
+                });
+            });
+        });
+        assert_eq!(spans.into_inner().unwrap(), vec![Some("caller"); 4]);
+    }
+
+    #[test]
+    fn test_rayon_stats() {
+        let threads = rayon::current_num_threads();
+        let _ = BUSY.set((0..threads).map(|_| AtomicU64::new(0)).collect());
+        let before = rayon_stats();
+        let start = Instant::now();
+        rayon_scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|_| {
+                    let start = Instant::now();
+                    while start.elapsed() < Duration::from_millis(20) {
+                        std::hint::spin_loop();
+                    }
+                });
+            }
+        });
+        let after = rayon_stats();
+        assert!(after.spawned >= before.spawned + threads as u64);
+        assert!(after.completed >= before.completed + threads as u64);
+        assert!(after.busy_time() > before.busy_time());
+        let utilization = after.utilization(&before, start.elapsed()).unwrap();
+        assert!(utilization > 0.0 && utilization <= 1.0, "{utilization}");
+    }
+
+    #[test]
+    fn test_busy_excludes_waiting() {
+        let running = || BUSY_SINCE.with(Cell::get).is_some();
+        let outer = Busy::enter(Some(0));
+        assert!(running());
+        let waiting = Waiting::enter();
+        assert!(!running());
+        // Tasks run while waiting are outermost tasks
+        let inner = Busy::enter(Some(0));
+        assert!(running());
+        drop(inner);
+        assert!(!running());
+        drop(waiting);
+        assert!(running());
+        drop(outer);
+        assert!(!running());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_compute() {
+        assert_eq!(spawn_compute(|| 2 + 2).await.unwrap(), 4);
+        let panic = tokio::spawn(spawn_compute(|| panic!("compute panic")))
+            .await
+            .unwrap_err();
+        assert!(panic.is_panic());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_pin_to_core() {
+        let core = *os::allowed_cores().unwrap().last().unwrap();
+        std::thread::spawn(move || {
+            os::pin_to_core(core).unwrap();
+            assert_eq!(os::allowed_cores().unwrap(), vec![core]);
+            os::set_nice(19).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+}
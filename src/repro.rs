@@ -0,0 +1,389 @@
+//! Repro bundles for failed runs.
+//!
+//! The invocation is recorded at startup. When the program fails the command
+//! line to reproduce the run, including the random seed, is logged, and with
+//! `--repro-dir` a bundle is written as a shell script that runs it.
+
+use crate::{default_from_clap, Version};
+use clap::{parser::ValueSource, Arg, ArgMatches, Command, Parser};
+use eyre::Report;
+use once_cell::sync::OnceCell;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs, mem,
+    path::{Path, PathBuf},
+    process::id as pid,
+    time::SystemTime,
+};
+use tracing::{error, info};
+
+static INVOCATION: OnceCell<Invocation> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Directory to write a repro bundle to when the program fails. The bundle
+    /// is a shell script with the version, seed and options of the run.
+    #[clap(long, env)]
+    repro_dir: Option<PathBuf>,
+}
+
+default_from_clap!(Options);
+
+impl Options {
+    /// Record the invocation for reproducing a failure.
+    pub fn init(self, command: &Command, matches: &ArgMatches) {
+        let vars = env::vars_os().collect();
+        let invocation = Invocation::new(self.repro_dir, env::args_os(), &vars, command, matches);
+        let _ = INVOCATION.set(invocation);
+    }
+}
+
+/// Log the command to reproduce the run and write the repro bundle.
+pub fn on_failure(version: &Version, report: &Report) {
+    let Some(invocation) = INVOCATION.get() else {
+        return;
+    };
+    let command = invocation.command(seed());
+    error!("To reproduce run: {}", command);
+    let Some(dir) = &invocation.dir else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("repro-{}-{}.sh", pid(), timestamp));
+    match write_script(&path, &invocation.bundle(version, report, &command)) {
+        Ok(()) => info!(path = %path.display(), "Repro bundle written"),
+        Err(err) => error!(?err, "Error writing repro bundle: {}", err),
+    }
+}
+
+/// The resolved options and environment of the run, and the command to
+/// reproduce it.
+pub fn summary() -> Vec<String> {
+    INVOCATION.get().map_or_else(Vec::new, |invocation| {
+        let mut lines = invocation.summary();
+        lines.push(String::new());
+        lines.push(format!("To reproduce run: {}", invocation.command(seed())));
+        lines
+    })
+}
+
+pub fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)
+}
+
+fn write_script(path: &Path, contents: &str) -> std::io::Result<()> {
+    write(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// The random seed, if random numbers were used.
+#[cfg(feature = "rand")]
+fn seed() -> Option<u64> {
+    crate::rand::used_seed()
+}
+
+#[cfg(not(feature = "rand"))]
+const fn seed() -> Option<u64> {
+    None
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Invocation {
+    dir:         Option<PathBuf>,
+    /// Program and arguments as given, with hidden values masked.
+    args:        Vec<String>,
+    /// Whether `--random-seed` was given on the command line.
+    seed_given:  bool,
+    /// Resolved option values with their source.
+    options:     Vec<(String, String, &'static str)>,
+    /// Environment variables of our options, `None` if the value is hidden.
+    environment: Vec<(String, Option<String>)>,
+}
+
+impl Invocation {
+    fn new(
+        dir: Option<PathBuf>,
+        args: impl IntoIterator<Item = OsString>,
+        vars: &HashMap<OsString, OsString>,
+        command: &Command,
+        matches: &ArgMatches,
+    ) -> Self {
+        let mut options = Vec::new();
+        let mut environment = Vec::new();
+        for arg in command.get_arguments() {
+            let hidden = is_hidden(arg);
+            if let Some(name) = arg.get_env() {
+                if let Some(value) = vars.get(name) {
+                    let value = (!hidden).then(|| value.to_string_lossy().into_owned());
+                    environment.push((name.to_string_lossy().into_owned(), value));
+                }
+            }
+            let id = arg.get_id().as_str();
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine) => "command line",
+                Some(ValueSource::EnvVariable) => "environment",
+                Some(ValueSource::DefaultValue) => "default",
+                _ => continue,
+            };
+            let name = arg
+                .get_long()
+                .map_or_else(|| id.to_owned(), |long| format!("--{long}"));
+            let values = if hidden {
+                HIDDEN.to_owned()
+            } else {
+                matches
+                    .get_raw(id)
+                    .into_iter()
+                    .flatten()
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            options.push((name, values, source));
+        }
+        let seed_given = options
+            .iter()
+            .any(|(name, _, source)| name == "--random-seed" && *source == "command line");
+        let args = args
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        Self {
+            dir,
+            args: mask_args(args, command),
+            seed_given,
+            options,
+            environment,
+        }
+    }
+
+    /// Shell command to reproduce the run. Hidden environment variables are
+    /// left to be set by the caller.
+    fn command(&self, seed: Option<u64>) -> String {
+        let mut words = self
+            .environment
+            .iter()
+            .filter_map(|(name, value)| Some(format!("{}={}", name, quote(value.as_ref()?))))
+            .chain(self.args.iter().map(|arg| quote(arg).into_owned()))
+            .collect::<Vec<_>>();
+        if let Some(seed) = seed.filter(|_| !self.seed_given) {
+            words.push(format!("--random-seed {seed:016x}"));
+        }
+        words.join(" ")
+    }
+
+    fn bundle(&self, version: &Version, report: &Report, command: &str) -> String {
+        let mut lines = vec![
+            "#!/bin/sh".to_owned(),
+            format!(
+                "# Repro bundle for a failed run of {} {}",
+                version.pkg_name, version.pkg_version
+            ),
+            "#".to_owned(),
+            format!("# Commit:  {}", version.commit_hash),
+            format!("# Target:  {}", version.target),
+            format!("# Rustc:   {}", version.rustc),
+            format!("# Profile: {}", version.profile),
+        ];
+        if let Some(seed) = seed() {
+            lines.push(format!("# Seed:    {seed:016x}"));
+        }
+        lines.push(format!(
+            "# Error:   {}",
+            report.to_string().replace('\n', "\n#          ")
+        ));
+        lines.push("#".to_owned());
+        lines.extend(
+            self.summary()
+                .iter()
+                .map(|line| format!("# {line}").trim_end().to_owned()),
+        );
+        lines.push(command.to_owned());
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// The options with their source and the environment.
+    fn summary(&self) -> Vec<String> {
+        let mut lines = vec!["Options:".to_owned()];
+        for (name, value, source) in &self.options {
+            lines.push(format!("  {name} = {value} ({source})"));
+        }
+        lines.push(String::new());
+        lines.push("Environment:".to_owned());
+        for (name, value) in &self.environment {
+            lines.push(value.as_ref().map_or_else(
+                || format!("  {name} is hidden and must be set to reproduce"),
+                |value| format!("  {name}={value}"),
+            ));
+        }
+        lines
+    }
+}
+
+/// Placeholder for the values of hidden arguments.
+const HIDDEN: &str = "<hidden>";
+
+/// Whether the values of the argument should not be shown, because it is
+/// hidden or hides its environment value.
+fn is_hidden(arg: &Arg) -> bool {
+    arg.is_hide_set() || arg.is_hide_env_values_set()
+}
+
+/// Replace the values of hidden arguments in `args` with [`HIDDEN`].
+fn mask_args(mut args: Vec<String>, command: &Command) -> Vec<String> {
+    let takes_values = |arg: &&Arg| arg.get_action().takes_values();
+    let long = |name: &str| {
+        command
+            .get_arguments()
+            .filter(takes_values)
+            .find(|arg| arg.get_long() == Some(name))
+    };
+    let short = |name: char| {
+        command
+            .get_arguments()
+            .filter(takes_values)
+            .find(|arg| arg.get_short() == Some(name))
+    };
+    let mut mask_next = false;
+    for word in args.iter_mut().skip(1) {
+        if mem::take(&mut mask_next) {
+            HIDDEN.clone_into(word);
+        } else if word == "--" {
+            break;
+        } else if let Some(name) = word.strip_prefix("--") {
+            match name.split_once('=') {
+                Some((name, _)) if long(name).is_some_and(is_hidden) => {
+                    *word = format!("--{name}={HIDDEN}");
+                }
+                Some(_) => {}
+                None => mask_next = long(name).is_some_and(is_hidden),
+            }
+        } else if let Some(shorts) = word.strip_prefix('-') {
+            // The first short option that takes a value takes the rest.
+            let found = shorts
+                .char_indices()
+                .find_map(|(index, name)| Some((index + name.len_utf8(), short(name)?)));
+            if let Some((end, _)) = found.filter(|(_, arg)| is_hidden(arg)) {
+                if end < shorts.len() {
+                    *word = format!("-{}{HIDDEN}", &shorts[..end]);
+                } else {
+                    mask_next = true;
+                }
+            }
+        }
+    }
+    args
+}
+
+/// Quote a word for the shell if needed.
+fn quote(word: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Arg;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("--log-format=json"), "--log-format=json");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_command() {
+        let vars = [
+            ("CLI_BATTERIES_TEST_REPRO_FORMAT", "json"),
+            ("CLI_BATTERIES_TEST_REPRO_TOKEN", "secret"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+        .collect();
+        let command = Command::new("test")
+            .arg(Arg::new("name").long("name"))
+            .arg(
+                Arg::new("format")
+                    .long("format")
+                    .env("CLI_BATTERIES_TEST_REPRO_FORMAT"),
+            )
+            .arg(
+                Arg::new("token")
+                    .long("token")
+                    .short('t')
+                    .env("CLI_BATTERIES_TEST_REPRO_TOKEN")
+                    .hide_env_values(true),
+            )
+            .arg(Arg::new("password").long("password").hide(true))
+            .arg(Arg::new("size").long("size").default_value("10"));
+        let args = [
+            "test",
+            "--name",
+            "a b",
+            "--token",
+            "secret",
+            "--password=secret",
+        ]
+        .map(OsString::from);
+        let matches = command.clone().get_matches_from(args.clone());
+        let invocation = Invocation::new(None, args, &vars, &command, &matches);
+        assert_eq!(invocation.options, vec![
+            ("--name".to_owned(), "a b".to_owned(), "command line"),
+            ("--token".to_owned(), "<hidden>".to_owned(), "command line"),
+            ("--password".to_owned(), "<hidden>".to_owned(), "command line"),
+            ("--size".to_owned(), "10".to_owned(), "default"),
+        ]);
+        assert_eq!(invocation.environment, vec![
+            ("CLI_BATTERIES_TEST_REPRO_FORMAT".to_owned(), Some("json".to_owned())),
+            ("CLI_BATTERIES_TEST_REPRO_TOKEN".to_owned(), None),
+        ]);
+        assert_eq!(
+            invocation.command(Some(0x1234)),
+            "CLI_BATTERIES_TEST_REPRO_FORMAT=json test --name 'a b' --token '<hidden>' \
+             '--password=<hidden>' --random-seed 0000000000001234"
+        );
+        assert_eq!(
+            invocation.command(None),
+            "CLI_BATTERIES_TEST_REPRO_FORMAT=json test --name 'a b' --token '<hidden>' \
+             '--password=<hidden>'"
+        );
+    }
+
+    #[test]
+    fn test_mask_args() {
+        let command = Command::new("test")
+            .arg(Arg::new("verbose").short('v').action(clap::ArgAction::Count))
+            .arg(Arg::new("token").short('t').hide_env_values(true))
+            .arg(Arg::new("hidden").long("hidden").hide(true).action(clap::ArgAction::SetTrue));
+        let mask = |args: &[&str]| {
+            mask_args(args.iter().map(|&arg| arg.to_owned()).collect(), &command)
+        };
+        assert_eq!(mask(&["test", "-vt", "secret"]), ["test", "-vt", "<hidden>"]);
+        assert_eq!(mask(&["test", "-tsecret", "-v"]), ["test", "-t<hidden>", "-v"]);
+        assert_eq!(mask(&["test", "--hidden", "file"]), ["test", "--hidden", "file"]);
+        assert_eq!(mask(&["test", "--", "-t", "file"]), ["test", "--", "-t", "file"]);
+    }
+}
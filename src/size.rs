@@ -0,0 +1,38 @@
+#![cfg(any(feature = "metered-allocator", feature = "rayon"))]
+use eyre::{bail, eyre, Result as EyreResult, WrapErr as _};
+
+/// Parse a byte size with an optional binary `K`, `M`, `G` or `T` suffix.
+pub fn parse_bytes(s: &str) -> EyreResult<usize> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        suffix => bail!("Invalid size suffix: {}", suffix),
+    };
+    let value: usize = digits.trim().parse().wrap_err("Invalid size")?;
+    // A terabyte does not fit in 32 bits.
+    1_usize
+        .checked_shl(shift)
+        .and_then(|unit| value.checked_mul(unit))
+        .ok_or_else(|| eyre!("Size too large: {}", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1234").unwrap(), 1234);
+        assert_eq!(parse_bytes("4k").unwrap(), 4096);
+        assert_eq!(parse_bytes("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_bytes("2GiB").unwrap(), 2 << 30);
+        assert!(parse_bytes("2X").is_err());
+        assert!(parse_bytes("M").is_err());
+        assert!(parse_bytes(&format!("{}T", usize::MAX)).is_err());
+    }
+}
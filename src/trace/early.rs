@@ -0,0 +1,240 @@
+//! Buffered logging before the log system is initialized.
+//!
+//! Events logged before the subscriber is installed, such as errors parsing
+//! the arguments or creating the runtime, are kept by a thread local
+//! subscriber. They are replayed through the log output once it is
+//! initialized, or written directly if the program fails before that.
+//! Replayed events have the time at which they are replayed.
+//!
+//! Only events on the main thread are kept, so tasks and threads that log are
+//! started after the log system is initialized.
+
+use std::{
+    cell::RefCell,
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use tracing::{
+    dispatcher::{self, DefaultGuard},
+    field::{display, DisplayValue, Field, Value, Visit},
+    span::{Attributes, Id, Record},
+    warn, Dispatch, Event, Metadata, Subscriber,
+};
+
+/// Maximum number of events to keep.
+const MAX_EVENTS: usize = 1000;
+
+/// Maximum number of fields of an event, as value sets are built from arrays.
+const MAX_FIELDS: usize = 32;
+
+static EVENTS: Mutex<Vec<Buffered>> = Mutex::new(Vec::new());
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static TRUNCATED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static GUARD: RefCell<Option<DefaultGuard>> = const { RefCell::new(None) };
+}
+
+/// Buffer the events logged on this thread until [`replay`] is called.
+pub fn install() {
+    let guard = dispatcher::set_default(&Dispatch::new(EarlySubscriber));
+    GUARD.with(|cell| *cell.borrow_mut() = Some(guard));
+}
+
+/// Stop buffering and log the buffered events through the current subscriber.
+pub fn replay() {
+    replay_events(take());
+}
+
+/// Stop buffering and log the buffered events through `dispatch`.
+pub fn replay_with(dispatch: &Dispatch) {
+    // Removing the buffering subscriber restores the default from before it
+    // was installed, so it has to be removed before `dispatch` is set.
+    let events = take();
+    dispatcher::with_default(dispatch, || replay_events(events));
+}
+
+fn replay_events(events: Vec<Buffered>) {
+    for event in events {
+        event.dispatch();
+    }
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!(dropped, "Dropped early log events");
+    }
+    let truncated = TRUNCATED.swap(0, Ordering::Relaxed);
+    if truncated > 0 {
+        warn!(truncated, max_fields = MAX_FIELDS, "Dropped fields of early log events");
+    }
+}
+
+fn take() -> Vec<Buffered> {
+    drop(GUARD.with(|cell| cell.borrow_mut().take()));
+    mem::take(&mut *EVENTS.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+struct EarlySubscriber;
+
+impl Subscriber for EarlySubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_event()
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut events = EVENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if events.len() >= MAX_EVENTS {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut buffered = Buffered {
+            metadata: event.metadata(),
+            values:   Vec::new(),
+        };
+        event.record(&mut buffered);
+        if buffered.values.len() > MAX_FIELDS {
+            buffered.values.truncate(MAX_FIELDS);
+            TRUNCATED.fetch_add(1, Ordering::Relaxed);
+        }
+        events.push(buffered);
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+/// An event with its values, to be dispatched again.
+struct Buffered {
+    metadata: &'static Metadata<'static>,
+    values:   Vec<(Field, Recorded)>,
+}
+
+enum Recorded {
+    F64(f64),
+    I64(i64),
+    U64(u64),
+    Bool(bool),
+    Str(String),
+    Debug(DisplayValue<String>),
+}
+
+impl Recorded {
+    fn as_value(&self) -> &dyn Value {
+        match self {
+            Self::F64(value) => value,
+            Self::I64(value) => value,
+            Self::U64(value) => value,
+            Self::Bool(value) => value,
+            Self::Str(value) => value,
+            Self::Debug(value) => value,
+        }
+    }
+}
+
+impl Buffered {
+    fn dispatch(&self) {
+        let fields = self.metadata.fields();
+        // Value sets are built from arrays, so pad to the maximum number of fields.
+        let Some(padding) = fields.iter().next() else {
+            return;
+        };
+        let mut values: [(&Field, Option<&dyn Value>); MAX_FIELDS] = [(&padding, None); MAX_FIELDS];
+        for (slot, (field, value)) in values.iter_mut().zip(&self.values) {
+            *slot = (field, Some(value.as_value()));
+        }
+        let values = fields.value_set(&values);
+        let event = Event::new(self.metadata, &values);
+        dispatcher::get_default(|dispatch| {
+            if dispatch.enabled(self.metadata) {
+                dispatch.event(&event);
+            }
+        });
+    }
+}
+
+impl Visit for Buffered {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.values.push((field.clone(), Recorded::F64(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.values.push((field.clone(), Recorded::I64(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.values.push((field.clone(), Recorded::U64(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.values.push((field.clone(), Recorded::Bool(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.values
+            .push((field.clone(), Recorded::Str(value.to_owned())));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.values
+            .push((field.clone(), Recorded::Debug(display(format!("{value:?}")))));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tracing::info;
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        Layer, Registry,
+    };
+
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Messages {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut buffered = Buffered {
+                metadata: event.metadata(),
+                values:   Vec::new(),
+            };
+            event.record(&mut buffered);
+            let message = buffered
+                .values
+                .iter()
+                .map(|(field, value)| match value {
+                    Recorded::Debug(value) => format!("{field}={value:?}"),
+                    Recorded::U64(value) => format!("{field}={value}u64"),
+                    Recorded::Str(value) => format!("{field}={value:?}"),
+                    _ => field.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.0.lock().unwrap().push(message);
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        install();
+        info!(id = 1_u64, host = "example.com", "Connecting to {}", "server");
+        let messages = Arc::default();
+        let subscriber = Registry::default().with(Messages(Arc::clone(&messages)));
+        replay_with(&Dispatch::new(subscriber));
+        assert_eq!(*messages.lock().unwrap(), vec![
+            r#"message=Connecting to server id=1u64 host="example.com""#.to_owned()
+        ]);
+    }
+}
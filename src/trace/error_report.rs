@@ -0,0 +1,256 @@
+//! Structured error reports for the JSON log formats.
+//!
+//! The error the program fails with is logged with a `report` field holding
+//! the report serialized as JSON. [`ReportFormatter`] embeds it in the output
+//! as an object instead of a string, so log indexes can search on its causes.
+
+use std::fmt::{Error, Result};
+
+use color_eyre::Handler;
+use eyre::Report;
+use serde_json::{json, Value};
+use tracing::{Event, Subscriber};
+use tracing_error::{ExtractSpanTrace, SpanTrace};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    registry::LookupSpan,
+};
+
+/// Name of the field holding the serialized report.
+pub const FIELD: &str = "report";
+
+/// An error report with its chain of causes, span trace, backtrace and notes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub message:    String,
+    pub causes:     Vec<String>,
+    pub span_trace: Vec<Span>,
+    pub backtrace:  Vec<Frame>,
+    pub notes:      Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub name:   &'static str,
+    pub target: &'static str,
+    pub fields: String,
+    pub file:   Option<&'static str>,
+    pub line:   Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub file:     Option<String>,
+    pub line:     Option<u32>,
+}
+
+impl ErrorReport {
+    pub fn new(report: &Report) -> Self {
+        let handler = report.handler().downcast_ref::<Handler>();
+        let span_trace = handler
+            .and_then(Handler::span_trace)
+            .or_else(|| report.chain().rev().find_map(|error| error.span_trace()));
+        let mut backtrace = Vec::new();
+        if let Some(trace) = handler.and_then(Handler::backtrace) {
+            for frame in trace.frames() {
+                for symbol in frame.symbols() {
+                    backtrace.push(Frame {
+                        function: symbol.name().map(|name| format!("{name:#}")),
+                        file:     symbol.filename().map(|file| file.display().to_string()),
+                        line:     symbol.lineno(),
+                    });
+                }
+            }
+        }
+        Self {
+            message: report.to_string(),
+            causes: report.chain().skip(1).map(ToString::to_string).collect(),
+            span_trace: span_trace.map(spans).unwrap_or_default(),
+            backtrace,
+            notes: notes(&format!("{report:?}")),
+        }
+    }
+
+    /// The innermost cause, or the message if there are no causes.
+    pub fn root_cause(&self) -> &str {
+        self.causes.last().unwrap_or(&self.message)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "message": self.message,
+            "causes": self.causes,
+            "root_cause": self.root_cause(),
+            "span_trace": self.span_trace.iter().map(|span| json!({
+                "name": span.name,
+                "target": span.target,
+                "fields": span.fields,
+                "file": span.file,
+                "line": span.line,
+            })).collect::<Vec<_>>(),
+            "backtrace": self.backtrace.iter().map(|frame| json!({
+                "function": frame.function,
+                "file": frame.file,
+                "line": frame.line,
+            })).collect::<Vec<_>>(),
+            "notes": self.notes,
+        })
+    }
+}
+
+fn spans(span_trace: &SpanTrace) -> Vec<Span> {
+    let mut spans = Vec::new();
+    span_trace.with_spans(|metadata, fields| {
+        spans.push(Span {
+            name:   metadata.name(),
+            target: metadata.target(),
+            fields: fields.to_owned(),
+            file:   metadata.file(),
+            line:   metadata.line(),
+        });
+        true
+    });
+    spans
+}
+
+/// The notes, warnings and suggestions added with `color_eyre::Section`.
+///
+/// `color-eyre` does not expose the sections of a report, so they are taken
+/// from its rendering.
+fn notes(rendered: &str) -> Vec<String> {
+    strip_ansi(rendered)
+        .lines()
+        .filter(|line| {
+            ["Note: ", "Warning: ", "Suggestion: "]
+                .iter()
+                .any(|prefix| line.starts_with(prefix))
+        })
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Remove the ANSI color codes from rendered text.
+pub fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the control sequence up to and including its final byte.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Wraps a JSON event formatter to embed serialized reports as objects.
+pub struct ReportFormatter<Inner>(Inner);
+
+impl<Inner> ReportFormatter<Inner> {
+    pub const fn new(inner: Inner) -> Self {
+        Self(inner)
+    }
+}
+
+impl<Inner, S, N> FormatEvent<S, N> for ReportFormatter<Inner>
+where
+    Inner: FormatEvent<S, N>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> Result {
+        if event.metadata().fields().field(FIELD).is_none() {
+            return self.0.format_event(ctx, writer, event);
+        }
+        let mut line = FormattedFields::<()>::new(String::new());
+        self.0.format_event(ctx, line.as_writer(), event)?;
+        let mut value = serde_json::from_str(&line.fields).map_err(|_| Error)?;
+        embed(&mut value);
+        writeln!(writer, "{value}")
+    }
+}
+
+/// Replace report fields holding serialized JSON by the parsed object.
+fn embed(value: &mut Value) {
+    if let Value::Object(map) = value {
+        for (key, value) in map {
+            let report = value
+                .as_str()
+                .filter(|_| key == FIELD)
+                .and_then(|json| serde_json::from_str(json).ok())
+                .filter(Value::is_object);
+            match report {
+                Some(report) => *value = report,
+                None => embed(value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use eyre::{eyre, WrapErr};
+
+    #[test]
+    fn test_to_json() {
+        let report = Err::<(), _>(eyre!("connection refused"))
+            .wrap_err("Error fetching config")
+            .unwrap_err();
+        let json = ErrorReport::new(&report).to_json();
+        assert_eq!(json["message"], "Error fetching config");
+        assert_eq!(json["causes"], json!(["connection refused"]));
+        assert_eq!(json["root_cause"], "connection refused");
+    }
+
+    #[test]
+    fn test_notes() {
+        use color_eyre::Section;
+        // Sections are only recorded with the `color-eyre` report handler,
+        // which can only be installed before the first report is created. Other
+        // tests create reports, so run this test alone in a new process.
+        if std::env::var_os("TEST_NOTES_PROCESS").is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "trace::error_report::test::test_notes"])
+                .env("TEST_NOTES_PROCESS", "1")
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{output:?}");
+            assert!(String::from_utf8_lossy(&output.stdout).contains(" 1 passed"));
+            return;
+        }
+        let (_, eyre_hook) = color_eyre::config::HookBuilder::default().into_hooks();
+        eyre_hook.install().unwrap();
+        let report = eyre!("Error fetching config")
+            .note("Is the server running?")
+            .suggestion("Start the server");
+        assert_eq!(ErrorReport::new(&report).notes, vec![
+            "Note: Is the server running?",
+            "Suggestion: Start the server"
+        ]);
+    }
+
+    #[test]
+    fn test_embed() {
+        let mut value = json!({
+            "fields": { "message": "failed", "report": r#"{"message":"failed"}"# },
+            "target": "report",
+        });
+        embed(&mut value);
+        assert_eq!(value["fields"]["report"], json!({ "message": "failed" }));
+        assert_eq!(value["target"], "report");
+    }
+}
@@ -0,0 +1,63 @@
+#![cfg(feature = "prometheus")]
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tracing::{Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{layer::Context, Layer};
+
+static EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "log_events_total",
+        "Number of log events by level and target.",
+        &["level", "target"]
+    )
+    .unwrap()
+});
+
+/// Layer counting log events. Apply the log output filter to it so the counts
+/// match what is written.
+pub struct EventMetrics;
+
+impl EventMetrics {
+    pub fn new() -> Self {
+        Lazy::force(&EVENTS);
+        Self
+    }
+}
+
+impl<S: Subscriber> Layer<S> for EventMetrics {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // Use the original target for events from the `log` crate.
+        let normalized_meta = event.normalized_metadata();
+        let meta = normalized_meta.as_ref().unwrap_or_else(|| event.metadata());
+        EVENTS
+            .with_label_values(&[meta.level().as_str(), meta.target()])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tracing::{debug, error, info, Level};
+    use tracing_subscriber::{filter::Targets, layer::SubscriberExt};
+
+    const TARGET: &str = module_path!();
+
+    #[test]
+    fn test_event_metrics() {
+        let filter = Targets::new().with_target(TARGET, Level::INFO);
+        let subscriber =
+            tracing_subscriber::registry().with(EventMetrics::new().with_filter(filter));
+        tracing::subscriber::with_default(subscriber, || {
+            error!("First");
+            error!("Second");
+            info!("Third");
+            debug!("Filtered");
+        });
+        let count = |level| EVENTS.with_label_values(&[level, TARGET]).get();
+        assert_eq!(count("ERROR"), 2);
+        assert_eq!(count("INFO"), 1);
+        assert_eq!(count("DEBUG"), 0);
+    }
+}
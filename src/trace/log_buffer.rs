@@ -0,0 +1,396 @@
+//! Ring buffer of recent log events.
+//!
+//! With `--log-backfill` the last events are kept at a more verbose level than
+//! the log output. When an error is logged, which includes panics, or on
+//! SIGUSR2, the kept events that were not logged are logged marked as
+//! backfill. Crash reports include the kept events.
+//!
+//! Events are written to the ring without locks. Readers temporarily take the
+//! events out of their slots, so a slot is owned by one thread at a time.
+
+use std::{
+    cell::Cell,
+    fmt::{self, Debug, Display, Formatter, Write},
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    thread,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use tracing::{
+    field::{Field, Visit},
+    info,
+    level_filters::LevelFilter,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{FilterExt, Targets},
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+
+use super::error_report::strip_ansi;
+
+static MIN_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static RING: OnceCell<Arc<Ring>> = OnceCell::new();
+
+thread_local! {
+    /// Set while logging the backfill, to not keep those events again.
+    static FLUSHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Keep at least the last `lines` events. Must be called before the log
+/// system is initialized.
+pub fn buffer_logs(lines: usize) {
+    MIN_CAPACITY.fetch_max(lines, Ordering::Relaxed);
+}
+
+/// The most recent events, oldest first.
+pub fn recent_logs() -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(ring) = RING.get() {
+        ring.visit(|entry| lines.push(entry.to_string()));
+    }
+    lines
+}
+
+/// Layer keeping the last `backfill` events matching `targets`, or the last
+/// events that are logged if only crash reports need them. `output` is the
+/// filter of the log output.
+pub fn layer<S>(
+    backfill: usize,
+    targets: Targets,
+    output: Targets,
+) -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let capacity = backfill.max(MIN_CAPACITY.load(Ordering::Relaxed));
+    if capacity == 0 {
+        return None;
+    }
+    let ring = RING
+        .get_or_init(|| Arc::new(Ring::new(capacity, backfill > 0)))
+        .clone();
+    let targets = if backfill > 0 { targets } else { output.clone() };
+    let flush = if ring.backfill {
+        spawn_flusher(ring.clone())
+    } else {
+        None
+    };
+    Some(Box::new(
+        LogBuffer {
+            ring,
+            output,
+            flush,
+        }
+        .with_filter(targets.or(LevelFilter::ERROR)),
+    ))
+}
+
+/// Start a thread that logs the backfill when requested. Events logged while
+/// an event is dispatched are dropped, so errors can not log the backfill
+/// themselves.
+fn spawn_flusher(ring: Arc<Ring>) -> Option<SyncSender<()>> {
+    // One pending request is enough, as it flushes everything before it.
+    let (sender, requests) = sync_channel(1);
+    thread::Builder::new()
+        .name("log-backfill".to_owned())
+        .spawn(move || {
+            for () in requests {
+                ring.flush();
+            }
+        })
+        .ok()?;
+    Some(sender)
+}
+
+/// Whether the event is part of the backfill, which is logged regardless of
+/// the log filter.
+pub fn is_backfill(meta: &Metadata<'_>) -> bool {
+    meta.target() == module_path!() && meta.fields().field("backfill").is_some()
+}
+
+/// Log the backfill on SIGUSR2.
+#[cfg(all(unix, feature = "signals"))]
+pub fn watch_signal() {
+    use crate::shutdown::await_shutdown;
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::error;
+
+    tokio::spawn(async {
+        let mut sigusr2 = match signal(SignalKind::user_defined2()) {
+            Ok(signal) => signal,
+            Err(err) => {
+                error!(?err, "Error handling SIGUSR2: {}", err);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                () = await_shutdown() => break,
+                _ = sigusr2.recv() => {},
+            };
+            if let Some(ring) = RING.get().filter(|ring| ring.backfill) {
+                info!("SIGUSR2 received, logging backfill");
+                ring.flush();
+            } else {
+                info!("SIGUSR2 received, but the log backfill is disabled");
+            }
+        }
+    });
+}
+
+struct Entry {
+    seq:     usize,
+    time:    DateTime<Utc>,
+    level:   Level,
+    target:  &'static str,
+    message: String,
+    logged:  bool,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:>5} {}:{}",
+            self.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+struct Ring {
+    slots:    Box<[AtomicPtr<Entry>]>,
+    next:     AtomicUsize,
+    backfill: bool,
+}
+
+impl Ring {
+    fn new(capacity: usize, backfill: bool) -> Self {
+        Self {
+            slots: (0..capacity)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            next: AtomicUsize::new(0),
+            backfill,
+        }
+    }
+
+    fn push(&self, mut entry: Entry) {
+        entry.seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[entry.seq % self.slots.len()];
+        let old = slot.swap(Box::into_raw(Box::new(entry)), Ordering::AcqRel);
+        if !old.is_null() {
+            // SAFETY: Slots hold pointers from `Box::into_raw` and the swap
+            // gave us the only copy.
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    /// Call `f` on the entries, oldest first.
+    fn visit(&self, mut f: impl FnMut(&mut Entry)) {
+        let mut taken = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                let entry = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+                // SAFETY: As in `push`.
+                (!entry.is_null()).then(|| (slot, unsafe { Box::from_raw(entry) }))
+            })
+            .collect::<Vec<_>>();
+        taken.sort_by_key(|(_, entry)| entry.seq);
+        for (_, entry) in &mut taken {
+            f(entry);
+        }
+        for (slot, entry) in taken {
+            let entry = Box::into_raw(entry);
+            if slot
+                .compare_exchange(ptr::null_mut(), entry, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+            {
+                // A newer event was written to the slot in the meantime.
+                // SAFETY: The pointer was not published.
+                drop(unsafe { Box::from_raw(entry) });
+            }
+        }
+    }
+
+    /// Log the entries that were not logged yet.
+    fn flush(&self) {
+        if FLUSHING.with(|flushing| flushing.replace(true)) {
+            return;
+        }
+        self.visit(|entry| {
+            if !entry.logged {
+                entry.logged = true;
+                info!(
+                    backfill = true,
+                    backfill.time = %entry.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+                    backfill.level = %entry.level,
+                    backfill.target = entry.target,
+                    "{}",
+                    entry.message.trim_start()
+                );
+            }
+        });
+        FLUSHING.with(|flushing| flushing.set(false));
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        for slot in &mut *self.slots {
+            let entry = *slot.get_mut();
+            if !entry.is_null() {
+                // SAFETY: As in `push`.
+                drop(unsafe { Box::from_raw(entry) });
+            }
+        }
+    }
+}
+
+struct LogBuffer {
+    ring:   Arc<Ring>,
+    output: Targets,
+    /// Requests for the flusher thread to log the backfill.
+    flush:  Option<SyncSender<()>>,
+}
+
+impl<S: Subscriber> Layer<S> for LogBuffer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if FLUSHING.with(Cell::get) {
+            return;
+        }
+        let meta = event.metadata();
+        if let Some(flush) = self.flush.as_ref().filter(|_| *meta.level() == Level::ERROR) {
+            // If full, the pending flush also logs the events before this one.
+            let _ = flush.try_send(());
+        }
+        let mut message = String::new();
+        event.record(&mut MessageVisitor(&mut message));
+        self.ring.push(Entry {
+            seq:     0,
+            time:    Utc::now(),
+            level:   *meta.level(),
+            target:  meta.target(),
+            message: strip_ansi(&message),
+            logged:  self.output.would_enable(meta.target(), meta.level()),
+        });
+    }
+}
+
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let _ = if field.name() == "message" {
+            write!(self.0, " {value:?}")
+        } else {
+            write!(self.0, " {}={value:?}", field.name())
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use tracing::{debug, error, subscriber::with_default};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    fn messages(ring: &Ring) -> Vec<(String, bool)> {
+        let mut messages = Vec::new();
+        ring.visit(|entry| messages.push((entry.message.clone(), entry.logged)));
+        messages
+    }
+
+    #[test]
+    fn test_ring() {
+        let ring = Ring::new(2, false);
+        for i in 0..3 {
+            ring.push(Entry {
+                seq:     0,
+                time:    Utc::now(),
+                level:   Level::INFO,
+                target:  "test",
+                message: format!(" {i}"),
+                logged:  true,
+            });
+        }
+        let expected = vec![(" 1".to_owned(), true), (" 2".to_owned(), true)];
+        assert_eq!(messages(&ring), expected);
+    }
+
+    /// Records the fields of backfill events, except the time.
+    struct Backfill(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Backfill {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            struct Fields(Vec<String>);
+
+            impl Visit for Fields {
+                fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                    if field.name() != "backfill.time" {
+                        self.0.push(format!("{field}={value:?}"));
+                    }
+                }
+            }
+
+            if is_backfill(event.metadata()) {
+                let mut fields = Fields(Vec::new());
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0.join(" "));
+            }
+        }
+    }
+
+    #[test]
+    fn test_backfill() {
+        let ring = Arc::new(Ring::new(10, true));
+        let (flush, requests) = sync_channel(1);
+        let layer = LogBuffer {
+            ring:   ring.clone(),
+            output: Targets::new().with_default(Level::INFO),
+            flush:  Some(flush),
+        };
+        let backfill = Arc::default();
+        let subscriber = Registry::default()
+            .with(layer)
+            .with(Backfill(Arc::clone(&backfill)));
+        with_default(subscriber, || {
+            debug!(id = 1, "Connecting");
+            let expected = vec![(" Connecting id=1".to_owned(), false)];
+            assert_eq!(messages(&ring), expected);
+            error!("Connection failed");
+            error!("Connection failed again");
+
+            // Errors only request the flush, which is done by the flusher.
+            assert!(requests.try_recv().is_ok());
+            assert!(requests.try_recv().is_err());
+            assert!(backfill.lock().unwrap().is_empty());
+            ring.flush();
+        });
+        assert_eq!(*backfill.lock().unwrap(), vec![format!(
+            "message=Connecting id=1 backfill=true backfill.level=DEBUG backfill.target={:?}",
+            module_path!()
+        )]);
+
+        // The backfill itself is not kept
+        let expected = vec![
+            (" Connecting id=1".to_owned(), true),
+            (" Connection failed".to_owned(), true),
+            (" Connection failed again".to_owned(), true),
+        ];
+        assert_eq!(messages(&ring), expected);
+    }
+}
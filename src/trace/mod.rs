@@ -1,28 +1,42 @@
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
+mod early;
+mod error_report;
+mod event_metrics;
 mod formats;
+mod log_buffer;
 mod open_telemetry;
+mod span_alloc;
 mod span_formatter;
+mod span_metrics;
 mod tiny_log_fmt;
 mod tokio_console;
 mod utils;
 
 use core::str::FromStr;
 use std::{
-    cmp::max, env, fs::File, io::BufWriter, path::PathBuf, process::id as pid,
-    thread::available_parallelism,
+    cmp::max,
+    env,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    process::id as pid,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
 };
 
 use ::clap::ArgAction;
 use clap::Parser;
-use eyre::{bail, eyre, Error as EyreError, Result as EyreResult, WrapErr as _};
+use eyre::{bail, eyre, Error as EyreError, Report, Result as EyreResult, WrapErr as _};
 use once_cell::sync::OnceCell;
-use tracing::{info, Level, Subscriber};
+use tracing::{error, info, Dispatch, Level, Subscriber};
 use tracing_error::ErrorLayer;
 use tracing_flame::{FlameLayer, FlushGuard};
 use tracing_log::{InterestCacheConfig, LogTracer};
 use tracing_subscriber::{
-    filter::Targets,
+    filter::{filter_fn, FilterExt, Targets},
     fmt::{self},
     layer::SubscriberExt,
     Layer, Registry,
@@ -33,11 +47,29 @@ use users::{get_current_gid, get_current_uid};
 #[cfg(feature = "opentelemetry")]
 #[allow(clippy::useless_attribute, clippy::module_name_repetitions)]
 pub use self::open_telemetry::{trace_from_headers, trace_to_headers};
-use self::{span_formatter::SpanFormatter, tiny_log_fmt::TinyLogFmt};
-use crate::{default_from_clap, Version};
+pub use self::{
+    error_report::ErrorReport,
+    log_buffer::{buffer_logs, recent_logs},
+};
+#[cfg(all(unix, feature = "signals"))]
+pub use self::log_buffer::watch_signal as watch_backfill_signal;
+use self::{
+    error_report::ReportFormatter, span_formatter::SpanFormatter, tiny_log_fmt::TinyLogFmt,
+};
+use crate::{cgroup, default_from_clap, Version};
 
 static FLAME_FLUSH_GUARD: OnceCell<Option<FlushGuard<BufWriter<File>>>> = OnceCell::new();
 
+/// Whether the log format is JSON, for logging structured error reports.
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+/// Log format for the early events if the program fails before the log
+/// system is initialized.
+static EARLY_FORMAT: Mutex<LogFormat> = Mutex::new(LogFormat::Tiny);
+
+/// Log filter for the early events, `INFO` if the arguments were not parsed.
+static EARLY_TARGETS: Mutex<Option<Targets>> = Mutex::new(None);
+
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Hash, Eq)]
 enum LogFormat {
     Tiny,
@@ -71,19 +103,29 @@ impl LogFormat {
                     .json()
                     .with_current_span(true)
                     .with_span_list(false)
-                    .map_event_format(SpanFormatter::new),
+                    .map_event_format(SpanFormatter::new)
+                    .map_event_format(ReportFormatter::new),
             ),
             #[cfg(feature = "otlp")]
             Self::Otlp => Box::new(
                 layer
                     .json()
                     .event_format(formats::otlp::OtlpFormatter)
-                    .map_event_format(SpanFormatter::new),
+                    .map_event_format(SpanFormatter::new)
+                    .map_event_format(ReportFormatter::new),
             ),
             #[cfg(feature = "datadog")]
-            Self::Datadog => Box::new(layer.json().event_format(formats::datadog::DataDogFormat)),
+            Self::Datadog => Box::new(
+                layer
+                    .json()
+                    .event_format(ReportFormatter::new(formats::datadog::DataDogFormat)),
+            ),
         }
     }
+
+    const fn is_structured(self) -> bool {
+        !matches!(self, Self::Tiny | Self::Compact | Self::Pretty)
+    }
 }
 
 impl FromStr for LogFormat {
@@ -120,10 +162,28 @@ pub struct Options {
     #[clap(long, env, default_value = "tiny")]
     log_format: LogFormat,
 
+    /// Keep this many recent events that are not logged, and log them marked
+    /// as backfill when an error is logged or on SIGUSR2.
+    #[clap(long, env, default_value = "0")]
+    log_backfill: usize,
+
+    /// Events to keep for the backfill, using the same syntax as
+    /// `--log-filter`. Defaults to two levels more verbose than `--verbose`.
+    #[clap(long, env, default_value_t)]
+    log_backfill_filter: String,
+
     /// Store traces in a flame graph file for processing with inferno.
     #[clap(long, env)]
     trace_flame: Option<PathBuf>,
 
+    /// Record call, error and duration metrics for spans matching this filter,
+    /// using the same syntax as `--log-filter`. Defaults to the app crates at
+    /// INFO level. With the metered allocator, this also selects the spans
+    /// that allocations are attributed to.
+    #[cfg(any(feature = "prometheus", feature = "metered-allocator"))]
+    #[clap(long, env, default_value_t)]
+    span_metrics: String,
+
     #[cfg(feature = "tokio-console")]
     #[clap(flatten)]
     pub tokio_console: tokio_console::Options,
@@ -136,29 +196,28 @@ pub struct Options {
 default_from_clap!(Options);
 
 impl Options {
-    #[allow(clippy::borrow_as_ptr)] // ptr::addr_of! does not work here.
-    pub fn init(&self, version: &Version, load_addr: usize) -> EyreResult<()> {
+    /// Use the configured log format and filter for the early events, in case
+    /// the program fails before the log system is initialized.
+    pub fn set_early_output(&self, version: &Version) {
+        set_early_format(self.log_format);
+        if let Ok(targets) = self.targets(self.verbose(), version) {
+            *EARLY_TARGETS.lock().unwrap_or_else(PoisonError::into_inner) = Some(targets);
+        }
+    }
+
+    /// The `--verbose` count, or the `VERBOSE` environment variable if higher.
+    fn verbose(&self) -> u8 {
         // Hack: ENV parsing for a `action = ArgAction::Count` argument
         // is not supported. So we have to do it manually.
-        let verbose = env::var("VERBOSE")
+        env::var("VERBOSE")
             .ok()
             .and_then(|s| s.parse().ok())
-            .map_or(self.verbose, |e| max(e, self.verbose));
-
-        // Log filtering is a combination of `--log-filter` and `--verbose` arguments.
-        let verbosity = {
-            let (all, app) = match verbose {
-                0 => (Level::ERROR, Level::INFO),
-                1 => (Level::INFO, Level::INFO),
-                2 => (Level::INFO, Level::DEBUG),
-                3 => (Level::INFO, Level::TRACE),
-                4 => (Level::DEBUG, Level::TRACE),
-                _ => (Level::TRACE, Level::TRACE),
-            };
-            Targets::new()
-                .with_default(all)
-                .with_targets(version.app_crates.iter().map(|c| (c, app)))
-        };
+            .map_or(self.verbose, |e| max(e, self.verbose))
+    }
+
+    /// Log filtering is a combination of `--log-filter` and `--verbose` arguments.
+    fn targets(&self, verbose: u8, version: &Version) -> EyreResult<Targets> {
+        let verbosity = verbosity_targets(verbose, version);
         let log_filter = if self.log_filter.is_empty() {
             Targets::new()
         } else {
@@ -166,7 +225,20 @@ impl Options {
                 .parse()
                 .wrap_err("Error parsing log-filter")?
         };
-        let targets = verbosity.with_targets(log_filter);
+        Ok(verbosity.with_targets(log_filter))
+    }
+
+    #[allow(clippy::borrow_as_ptr)] // ptr::addr_of! does not work here.
+    pub fn init(&self, version: &Version, load_addr: usize) -> EyreResult<()> {
+        let verbose = self.verbose();
+        let targets = self.targets(verbose, version)?;
+        let backfill_targets = if self.log_backfill_filter.is_empty() {
+            verbosity_targets(verbose.saturating_add(2), version)
+        } else {
+            self.log_backfill_filter
+                .parse()
+                .wrap_err("Error parsing log-backfill-filter")?
+        };
 
         // Tracing stack
         let subscriber = Registry::default();
@@ -198,14 +270,42 @@ impl Options {
         #[cfg(feature = "tokio-console")]
         let subscriber = subscriber.with(self.tokio_console.into_layer());
 
+        // Prometheus metrics for spans
+        #[cfg(feature = "prometheus")]
+        let subscriber = subscriber.with(span_metrics::layer(&self.span_metrics, version)?);
+
+        // Allocations by span
+        #[cfg(feature = "metered-allocator")]
+        let subscriber = subscriber.with(span_alloc::layer(&self.span_metrics, version)?);
+
         // Include span traces in errors
         let subscriber = subscriber.with(ErrorLayer::default());
 
+        // Prometheus metrics for log events
+        #[cfg(feature = "prometheus")]
+        let subscriber =
+            subscriber.with(event_metrics::EventMetrics::new().with_filter(targets.clone()));
+
+        // Recent events for the backfill and crash reports
+        let subscriber = subscriber.with(log_buffer::layer(
+            self.log_backfill,
+            backfill_targets,
+            targets.clone(),
+        ));
+
         // Log output
-        let subscriber = subscriber.with(self.log_format.into_layer().with_filter(targets));
+        let subscriber = subscriber.with(
+            self.log_format
+                .into_layer()
+                .with_filter(targets.or(filter_fn(log_buffer::is_backfill))),
+        );
 
         // Install
         tracing::subscriber::set_global_default(subscriber)?;
+        STRUCTURED.store(self.log_format.is_structured(), Ordering::Relaxed);
+
+        // Log the events from before the log system was initialized
+        early::replay();
 
         // Route `log` crate events to `tracing`
         LogTracer::builder()
@@ -218,9 +318,10 @@ impl Options {
             pid = pid(),
             uid = get_current_uid(),
             gid = get_current_gid(),
-            cores = available_parallelism()?,
+            cores = cgroup::host_cpus(),
+            effective_cores = cgroup::effective_cpus(),
             main = load_addr,
-            commit = &version.commit_hash[..8],
+            commit = version.commit_hash.get(..8).unwrap_or(version.commit_hash),
             "{name} {version}",
             name = version.crate_name,
             version = version.pkg_version,
@@ -230,6 +331,68 @@ impl Options {
     }
 }
 
+/// Log filter for a `--verbose` count, for all crates and the app crates.
+fn verbosity_targets(verbose: u8, version: &Version) -> Targets {
+    let (all, app) = match verbose {
+        0 => (Level::ERROR, Level::INFO),
+        1 => (Level::INFO, Level::INFO),
+        2 => (Level::INFO, Level::DEBUG),
+        3 => (Level::INFO, Level::TRACE),
+        4 => (Level::DEBUG, Level::TRACE),
+        _ => (Level::TRACE, Level::TRACE),
+    };
+    Targets::new()
+        .with_default(all)
+        .with_targets(version.app_crates.iter().map(|c| (c, app)))
+}
+
+/// Parse the `--span-metrics` filter, defaulting to the app crates at `INFO`.
+#[cfg(any(feature = "prometheus", feature = "metered-allocator"))]
+fn span_targets(filter: &str, version: &Version) -> EyreResult<Targets> {
+    Ok(if filter.is_empty() {
+        Targets::new().with_targets(version.app_crates.iter().map(|c| (c, Level::INFO)))
+    } else {
+        filter.parse().wrap_err("Error parsing span-metrics filter")?
+    })
+}
+
+/// Buffer the events logged before [`Options::init`]. They are replayed
+/// through the log output once it is initialized.
+pub fn buffer_early_logs() {
+    if let Some(format) = env::var("LOG_FORMAT").ok().and_then(|f| f.parse().ok()) {
+        set_early_format(format);
+    }
+    early::install();
+}
+
+/// Write the buffered early events to stderr if the program fails before the
+/// log system is initialized.
+pub fn flush_early_logs() {
+    let format = *EARLY_FORMAT.lock().unwrap_or_else(PoisonError::into_inner);
+    let targets = EARLY_TARGETS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| Targets::new().with_default(Level::INFO));
+    let subscriber = Registry::default().with(format.into_layer().with_filter(targets));
+    early::replay_with(&Dispatch::new(subscriber));
+}
+
+fn set_early_format(format: LogFormat) {
+    *EARLY_FORMAT.lock().unwrap_or_else(PoisonError::into_inner) = format;
+    STRUCTURED.store(format.is_structured(), Ordering::Relaxed);
+}
+
+/// Log the error the program failed with. In the JSON formats the report is
+/// logged as an object with its causes, span trace, backtrace and notes.
+pub fn log_report(report: &Report) {
+    if STRUCTURED.load(Ordering::Relaxed) {
+        error!(report = %ErrorReport::new(report).to_json(), "{}", report);
+    } else {
+        error!(?report, "{}", report);
+    }
+}
+
 pub fn shutdown() -> EyreResult<()> {
     if let Some(Some(flush_guard)) = FLAME_FLUSH_GUARD.get() {
         flush_guard.flush()?;
@@ -253,7 +416,11 @@ pub mod test {
             verbose: 4,
             log_filter: "foo".to_owned(),
             log_format: LogFormat::Tiny,
+            log_backfill: 0,
+            log_backfill_filter: String::new(),
             trace_flame: None,
+            #[cfg(any(feature = "prometheus", feature = "metered-allocator"))]
+            span_metrics: String::new(),
             #[cfg(feature = "tokio-console")]
             tokio_console: tokio_console::Options::default(),
             #[cfg(feature = "opentelemetry")]
@@ -0,0 +1,181 @@
+#![cfg(feature = "metered-allocator")]
+use std::cell::RefCell;
+
+use eyre::Result as EyreResult;
+#[cfg(feature = "prometheus")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "prometheus")]
+use prometheus::{exponential_buckets, register_histogram_vec, HistogramVec};
+use tracing::{
+    dispatcher,
+    field::{FieldSet, Value},
+    span::{Attributes, Id},
+    Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{filter::filter_fn, layer::Context, registry::LookupSpan, Layer};
+
+use super::span_targets;
+use crate::{
+    metered_allocator::{thread_allocations, ThreadAllocations},
+    Version,
+};
+
+#[cfg(feature = "prometheus")]
+static BYTES: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "span_alloc_bytes",
+        "Bytes allocated while a span was entered.",
+        &["span", "target"],
+        exponential_buckets(1024.0, 4.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+thread_local! {
+    /// Spans entered on this thread with the thread's allocations at entry.
+    static ENTERED: RefCell<Vec<(Id, ThreadAllocations)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Create a layer attributing allocations to the spans matching `filter`.
+///
+/// Allocations are counted while a span is entered, including in nested
+/// spans. On close the totals are logged as `alloc.bytes` and `alloc.count`
+/// at the level and target of the span and, with the `prometheus` feature,
+/// recorded in the `span_alloc_bytes` histogram.
+pub fn layer<S>(filter: &str, version: &Version) -> EyreResult<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let targets = span_targets(filter, version)?;
+    #[cfg(feature = "prometheus")]
+    Lazy::force(&BYTES);
+    Ok(SpanAlloc.with_filter(filter_fn(move |meta: &Metadata| {
+        meta.is_span() && targets.would_enable(meta.target(), meta.level())
+    })))
+}
+
+struct SpanAlloc;
+
+impl<S> Layer<S> for SpanAlloc
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(ThreadAllocations::default());
+        }
+    }
+
+    fn on_enter(&self, id: &Id, _ctx: Context<'_, S>) {
+        let start = thread_allocations();
+        let _ = ENTERED.try_with(|entered| entered.borrow_mut().push((id.clone(), start)));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let end = thread_allocations();
+        let Ok(Some(start)) = ENTERED.try_with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let index = entered.iter().rposition(|(entered, _)| entered == id)?;
+            Some(entered.remove(index).1)
+        }) else {
+            return;
+        };
+        if let Some(span) = ctx.span(id) {
+            if let Some(total) = span.extensions_mut().get_mut::<ThreadAllocations>() {
+                total.bytes += end.bytes - start.bytes;
+                total.count += end.count - start.count;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(total) = span.extensions_mut().remove::<ThreadAllocations>() else {
+            return;
+        };
+        #[cfg(feature = "prometheus")]
+        #[allow(clippy::cast_precision_loss)]
+        BYTES
+            .with_label_values(&[span.name(), span.metadata().target()])
+            .observe(total.bytes as f64);
+        log_close(&id, span.metadata(), &total);
+    }
+}
+
+/// Log the totals as a child event of the span with the span's metadata, so it
+/// passes the same filters as the span itself. The log formats show it as the
+/// `alloc` event of the span.
+fn log_close(id: &Id, metadata: &'static Metadata<'static>, total: &ThreadAllocations) {
+    let fields = FieldSet::new(&["alloc.bytes", "alloc.count"], metadata.callsite());
+    let mut iter = fields.iter();
+    let (Some(bytes), Some(count)) = (iter.next(), iter.next()) else {
+        return;
+    };
+    let values: [(_, Option<&dyn Value>); 2] =
+        [(&bytes, Some(&total.bytes)), (&count, Some(&total.count))];
+    let values = fields.value_set(&values);
+    let event = Event::new_child_of(id.clone(), metadata, &values);
+    dispatcher::get_default(|dispatch| {
+        if dispatch.enabled(metadata) {
+            dispatch.event(&event);
+        }
+    });
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info_span, Event, Level};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TARGET: &str = module_path!();
+
+    /// Records the level, target and field names of events.
+    struct Events(Arc<Mutex<Vec<(Level, String, String)>>>);
+
+    impl<S: Subscriber> Layer<S> for Events {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let meta = event.metadata();
+            let fields = event
+                .fields()
+                .map(|f| f.name())
+                .collect::<Vec<_>>()
+                .join(",");
+            self.0
+                .lock()
+                .unwrap()
+                .push((*meta.level(), meta.target().to_owned(), fields));
+        }
+    }
+
+    #[test]
+    fn test_span_alloc() {
+        let version = Version::test();
+        let events = Arc::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(layer("", &version).unwrap())
+            .with(Events(Arc::clone(&events)));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("alloc_outer");
+            for _ in 0..2 {
+                let _guard = span.enter();
+                let _inner = info_span!("alloc_inner").entered();
+                drop(vec![0_u8; 1 << 20]);
+            }
+        });
+        let histogram = |span| BYTES.with_label_values(&[span, TARGET]);
+        assert_eq!(histogram("alloc_outer").get_sample_count(), 1);
+        assert!(histogram("alloc_outer").get_sample_sum() >= 2.0 * f64::from(1 << 20));
+        assert_eq!(histogram("alloc_inner").get_sample_count(), 2);
+        assert!(histogram("alloc_inner").get_sample_sum() >= 2.0 * f64::from(1 << 20));
+
+        // Logged at the level and target of the spans.
+        let close = (
+            Level::INFO,
+            TARGET.to_owned(),
+            "alloc.bytes,alloc.count".to_owned(),
+        );
+        assert_eq!(*events.lock().unwrap(), vec![close; 3]);
+    }
+}
@@ -55,11 +55,21 @@ where
             // Extract fields from event
             #[derive(Debug, Default)]
             struct Visitor {
-                time_busy: Option<String>,
-                time_idle: Option<String>,
+                time_busy:   Option<String>,
+                time_idle:   Option<String>,
+                alloc_bytes: Option<u64>,
+                alloc_count: Option<u64>,
             }
 
             impl Visit for Visitor {
+                fn record_u64(&mut self, field: &Field, value: u64) {
+                    match field.name() {
+                        "alloc.bytes" => self.alloc_bytes = Some(value),
+                        "alloc.count" => self.alloc_count = Some(value),
+                        _ => self.record_debug(field, &value),
+                    }
+                }
+
                 fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
                     match field.name() {
                         "time.busy" => self.time_busy = Some(format!("{:?}", value)),
@@ -81,7 +91,31 @@ where
 
             let message = span.name();
 
-            if let (Some(time_busy), Some(time_idle)) = (visitor.time_busy, visitor.time_idle) {
+            if let (Some(alloc_bytes), Some(alloc_count)) =
+                (visitor.alloc_bytes, visitor.alloc_count)
+            {
+                // Allocation totals, see `span_alloc`
+                let span = display("alloc");
+                let field_set =
+                    FieldSet::new(&["message", "span", "alloc.bytes", "alloc.count"], callsite);
+
+                let mut fields = field_set.iter();
+
+                let values = [
+                    (&fields.next().unwrap(), Some(&message as &dyn Value)),
+                    (&fields.next().unwrap(), Some(&span)),
+                    (&fields.next().unwrap(), Some(&alloc_bytes)),
+                    (&fields.next().unwrap(), Some(&alloc_count)),
+                ];
+
+                let value_set = field_set.value_set(&values);
+                let event =
+                    Event::new_child_of(event.parent().cloned(), event.metadata(), &value_set);
+
+                self.inner.format_event(ctx, writer, &event)?;
+            } else if let (Some(time_busy), Some(time_idle)) =
+                (visitor.time_busy, visitor.time_idle)
+            {
                 // Closing event
                 let time_busy = display(time_busy);
                 let time_idle = display(time_idle);
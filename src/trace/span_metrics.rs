@@ -0,0 +1,179 @@
+#![cfg(feature = "prometheus")]
+use std::{fmt::Debug, time::Instant};
+
+use eyre::Result as EyreResult;
+use once_cell::sync::Lazy;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::filter_fn,
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+
+use super::span_targets;
+use crate::Version;
+
+static CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "span_calls_total",
+        "Number of completed spans.",
+        &["span", "target"]
+    )
+    .unwrap()
+});
+static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "span_errors_total",
+        "Number of completed spans that had an error.",
+        &["span", "target"]
+    )
+    .unwrap()
+});
+static DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "span_duration_seconds",
+        "Duration of spans from creation to close in seconds.",
+        &["span", "target"]
+    )
+    .unwrap()
+});
+
+/// Create a layer recording call, error and duration (RED) metrics for spans
+/// matching `filter`.
+///
+/// An empty filter selects the app crates at `INFO` level, which includes
+/// `#[instrument]`ed functions by default.
+pub fn layer<S>(filter: &str, version: &Version) -> EyreResult<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let targets = span_targets(filter, version)?;
+    Lazy::force(&CALLS);
+    Lazy::force(&ERRORS);
+    Lazy::force(&DURATION);
+
+    // We need matching spans and all error events, as these mark spans failed.
+    Ok(SpanMetrics.with_filter(filter_fn(move |meta: &Metadata| {
+        if meta.is_span() {
+            targets.would_enable(meta.target(), meta.level())
+        } else {
+            *meta.level() == Level::ERROR
+        }
+    })))
+}
+
+struct SpanMetrics;
+
+struct Timing {
+    start: Instant,
+    error: bool,
+}
+
+/// Detects an `error` field on spans.
+struct ErrorVisitor(bool);
+
+impl Visit for ErrorVisitor {
+    fn record_debug(&mut self, field: &Field, _value: &dyn Debug) {
+        if field.name() == "error" {
+            self.0 = true;
+        }
+    }
+}
+
+impl<S> Layer<S> for SpanMetrics
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = ErrorVisitor(false);
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing {
+                start: Instant::now(),
+                error: visitor.0,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = ErrorVisitor(false);
+        values.record(&mut visitor);
+        if visitor.0 {
+            if let Some(span) = ctx.span(id) {
+                if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                    timing.error = true;
+                }
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                timing.error = true;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(timing) = span.extensions_mut().remove::<Timing>() else {
+            return;
+        };
+        let labels = [span.name(), span.metadata().target()];
+        CALLS.with_label_values(&labels).inc();
+        if timing.error {
+            ERRORS.with_label_values(&labels).inc();
+        }
+        DURATION
+            .with_label_values(&labels)
+            .observe(timing.start.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tracing::{error, info, info_span};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TARGET: &str = module_path!();
+
+    fn subscriber() -> impl Subscriber {
+        let version = Version::test();
+        tracing_subscriber::registry().with(layer("", &version).unwrap())
+    }
+
+    #[test]
+    fn test_span_metrics() {
+        tracing::subscriber::with_default(subscriber(), || {
+            for _ in 0..3 {
+                let _guard = info_span!("red_ok").entered();
+                info!("Nothing to see here");
+            }
+            {
+                let _guard = info_span!("red_err").entered();
+                error!("Something went wrong");
+            }
+            drop(info_span!("red_field", error = "bad"));
+        });
+        let labels = |span| [span, TARGET];
+        assert_eq!(CALLS.with_label_values(&labels("red_ok")).get(), 3);
+        assert_eq!(ERRORS.with_label_values(&labels("red_ok")).get(), 0);
+        assert_eq!(CALLS.with_label_values(&labels("red_err")).get(), 1);
+        assert_eq!(ERRORS.with_label_values(&labels("red_err")).get(), 1);
+        assert_eq!(ERRORS.with_label_values(&labels("red_field")).get(), 1);
+        assert_eq!(
+            DURATION
+                .with_label_values(&labels("red_ok"))
+                .get_sample_count(),
+            3
+        );
+    }
+}
@@ -7,9 +7,57 @@ pub struct Version {
     pub commit_hash:  &'static str,
     pub long_version: &'static str,
     pub target:       &'static str,
+    pub rustc:        &'static str,
+    pub profile:      &'static str,
     pub app_crates:   Vec<String>,
 }
 
+impl Version {
+    /// A version with only the package name and version known. Use the
+    /// [`version!`](crate::version!) macro to fill in the build information.
+    #[must_use]
+    pub fn new(pkg_name: &'static str, pkg_version: &'static str) -> Self {
+        // Like `CARGO_CRATE_NAME`, leaked as it lives as long as the program.
+        let crate_name = if pkg_name.contains('-') {
+            Box::leak(pkg_name.replace('-', "_").into_boxed_str())
+        } else {
+            pkg_name
+        };
+        Self {
+            pkg_name,
+            pkg_version,
+            pkg_repo: "",
+            crate_name,
+            commit_hash: "unknown",
+            long_version: pkg_version,
+            target: "unknown",
+            rustc: "unknown",
+            profile: "unknown",
+            app_crates: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Version {
+    /// Version used in tests.
+    #[must_use]
+    pub fn test() -> Self {
+        Self {
+            pkg_name:     "cli-test",
+            pkg_version:  "v0.0.0",
+            pkg_repo:     "https://github.com/recmo/cli-batteries",
+            crate_name:   "test",
+            commit_hash:  "7cdd3615368b7e2ed1e053f33628fe7f65e6a538",
+            long_version: "v0.0.0\nFirst release",
+            target:       "aarch64-apple-darwin",
+            rustc:        "rustc 1.69.0 (84c898d65 2023-04-16)",
+            profile:      "debug",
+            app_crates:   vec!["cli_batteries".to_owned()],
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! version {
     ($($c:ident),* ) => {
@@ -20,6 +68,9 @@ macro_rules! version {
             crate_name:   env!("CARGO_CRATE_NAME"),
             commit_hash:  env!("COMMIT_SHA"),
             target:       env!("TARGET"),
+            // Not set by the `build_rs` of older versions.
+            rustc:        option_env!("RUSTC_VERSION").unwrap_or("unknown"),
+            profile:      option_env!("BUILD_PROFILE").unwrap_or("unknown"),
             long_version: concat!(
                 env!("CARGO_PKG_VERSION"),
                 "\n",
@@ -48,3 +99,15 @@ macro_rules! version {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let version = Version::new("cli-test", "v0.0.0");
+        assert_eq!(version.crate_name, "cli_test");
+        assert_eq!(Version::new("cli", "v0.0.0").crate_name, "cli");
+    }
+}
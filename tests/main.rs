@@ -12,6 +12,8 @@ const MOCK_VERSION: Version = Version {
     commit_hash:  "7cdd3615368b7e2ed1e053f33628fe7f65e6a538",
     long_version: "v0.0.0 First release",
     target:       "aarch64-apple-darwin",
+    rustc:        "rustc 1.69.0 (84c898d65 2023-04-16)",
+    profile:      "debug",
     app_crates:   vec![],
 };
 